@@ -0,0 +1,43 @@
+use core::mem::size_of;
+
+/// `LruPerCpuHashMap<ProcessKey, IoStats>` of block IO per submitting process
+pub const PROCESS_BLOCK_STATS_MAP: &str = "PROCESS_BLOCK_STATS";
+/// `LruPerCpuHashMap<ProcessKey, IoStats>` of NVMe commands per submitting process
+pub const PROCESS_NVME_STATS_MAP: &str = "PROCESS_NVME_STATS";
+/// `LruPerCpuHashMap<u64, IoStats>` of block IO per cgroup id of the submitting task
+pub const CGROUP_BLOCK_STATS_MAP: &str = "CGROUP_BLOCK_STATS";
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[repr(C)]
+pub struct ProcessKey {
+    pub tgid: u32,
+    pub comm: [u8; 16],
+}
+
+impl ProcessKey {
+    pub const EMPTY: ProcessKey = ProcessKey {
+        tgid: 0,
+        comm: [0; 16],
+    };
+}
+
+const _: () = assert!(size_of::<ProcessKey>() == 20);
+
+/// Completed requests, bytes and cumulated latency of an IO source (process, cgroup).
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct IoStats {
+    pub requests: u64,
+    pub bytes: u64,
+    pub latency_ns: u64,
+}
+
+const _: () = assert!(size_of::<IoStats>() == 24);
+
+#[cfg(feature = "user")]
+mod user {
+    use super::*;
+
+    unsafe impl aya::Pod for ProcessKey {}
+    unsafe impl aya::Pod for IoStats {}
+}
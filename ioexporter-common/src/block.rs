@@ -0,0 +1,164 @@
+use core::mem::{offset_of, size_of};
+
+use crate::attribution::ProcessKey;
+
+/// `LruHashMap<u64, RequestTrackerEntry>` of in flight requests, keyed by request pointer
+pub const RQ_TRACKER_MAP: &str = "RQ_TRACKER";
+/// Histograms keyed by `DiskLatencyHistogramKey`: total latency, scheduler queue time,
+/// device service time and request size
+pub const BLOCK_HISTOGRAM_MAP: &str = "BLOCK_HISTOGRAM";
+pub const BLOCK_QUEUE_HISTOGRAM_MAP: &str = "BLOCK_QUEUE_HISTOGRAM";
+pub const BLOCK_SERVICE_HISTOGRAM_MAP: &str = "BLOCK_SERVICE_HISTOGRAM";
+pub const BLOCK_SIZE_HISTOGRAM_MAP: &str = "BLOCK_SIZE_HISTOGRAM";
+/// `HashMap<DiskHistogramKey, i64>` of requests in flight per device
+pub const BLOCK_INFLIGHT_MAP: &str = "BLOCK_INFLIGHT";
+/// Histogram keyed by `DiskHistogramKey` of the requests in flight when one is issued
+pub const BLOCK_QUEUE_DEPTH_HISTOGRAM_MAP: &str = "BLOCK_QUEUE_DEPTH_HISTOGRAM";
+
+/// `enum req_op` values, checked against the generated `vmlinux::req_op` by the eBPF
+/// programs so that the operation labels can't drift from the kernel.
+pub mod req_op {
+    pub const REQ_OP_READ: u8 = 0;
+    pub const REQ_OP_WRITE: u8 = 1;
+    pub const REQ_OP_FLUSH: u8 = 2;
+    pub const REQ_OP_DISCARD: u8 = 3;
+    pub const REQ_OP_SECURE_ERASE: u8 = 5;
+    pub const REQ_OP_WRITE_ZEROES: u8 = 9;
+    pub const REQ_OP_ZONE_OPEN: u8 = 10;
+    pub const REQ_OP_ZONE_CLOSE: u8 = 11;
+    pub const REQ_OP_ZONE_FINISH: u8 = 12;
+    pub const REQ_OP_ZONE_APPEND: u8 = 13;
+    pub const REQ_OP_ZONE_RESET: u8 = 15;
+    pub const REQ_OP_ZONE_RESET_ALL: u8 = 17;
+    pub const REQ_OP_DRV_IN: u8 = 34;
+    pub const REQ_OP_DRV_OUT: u8 = 35;
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[repr(C)]
+pub struct DiskLatencyHistogramKey {
+    pub major: i32,
+    pub minor: i32,
+    /// REQ_OP_* of the request
+    pub op: u8,
+    pub pad1: u8,
+    pub pad2: u16,
+}
+
+const _: () = assert!(size_of::<DiskLatencyHistogramKey>() == 12);
+const _: () = assert!(offset_of!(DiskLatencyHistogramKey, op) == 8);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[repr(C)]
+pub struct DiskHistogramKey {
+    pub major: i32,
+    pub minor: i32,
+}
+
+const _: () = assert!(size_of::<DiskHistogramKey>() == 8);
+
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct RequestTrackerEntry {
+    /// Timestamps of block_rq_insert and block_rq_issue, 0 when not seen
+    pub inserted: u64,
+    pub issued: u64,
+    /// 1 once the request is accounted in BLOCK_INFLIGHT
+    pub counted: u8,
+    /// 1 when process is the task that submitted the request
+    pub attributed: u8,
+    pub pad: u16,
+    pub process: ProcessKey,
+    /// cgroup v2 id of the submitting task, 0 when not captured
+    pub cgroup: u64,
+    /// start_time_ns of the request, to tell it from an earlier request that was
+    /// allocated at the same address and freed without completing
+    pub start_time: u64,
+    /// Size of the request when it was issued, 0 when not seen
+    pub bytes: u32,
+    /// Device the request is counted on in BLOCK_INFLIGHT
+    pub device: DiskHistogramKey,
+    pub pad2: u32,
+}
+
+impl RequestTrackerEntry {
+    pub const EMPTY: RequestTrackerEntry = RequestTrackerEntry {
+        inserted: 0,
+        issued: 0,
+        counted: 0,
+        attributed: 0,
+        pad: 0,
+        process: ProcessKey::EMPTY,
+        cgroup: 0,
+        start_time: 0,
+        bytes: 0,
+        device: DiskHistogramKey { major: 0, minor: 0 },
+        pad2: 0,
+    };
+}
+
+const _: () = assert!(size_of::<RequestTrackerEntry>() == 72);
+const _: () = assert!(offset_of!(RequestTrackerEntry, process) == 20);
+const _: () = assert!(offset_of!(RequestTrackerEntry, cgroup) == 40);
+
+#[cfg(feature = "user")]
+mod user {
+    use ebpf_histogram::Key;
+
+    use super::req_op::*;
+    use super::*;
+
+    // https://elixir.bootlin.com/linux/latest/source/include/linux/blk_types.h
+    fn operation(op: u8) -> Option<&'static str> {
+        let name = match op {
+            REQ_OP_READ => "read",
+            REQ_OP_WRITE => "write",
+            REQ_OP_FLUSH => "flush",
+            REQ_OP_DISCARD => "discard",
+            REQ_OP_SECURE_ERASE => "secure_erase",
+            REQ_OP_WRITE_ZEROES => "write_zeroes",
+            REQ_OP_ZONE_OPEN => "zone_open",
+            REQ_OP_ZONE_CLOSE => "zone_close",
+            REQ_OP_ZONE_FINISH => "zone_finish",
+            REQ_OP_ZONE_APPEND => "zone_append",
+            REQ_OP_ZONE_RESET => "zone_reset",
+            REQ_OP_ZONE_RESET_ALL => "zone_reset_all",
+            REQ_OP_DRV_IN => "drv_in",
+            REQ_OP_DRV_OUT => "drv_out",
+            _ => return None,
+        };
+        Some(name)
+    }
+
+    unsafe impl aya::Pod for DiskLatencyHistogramKey {}
+    impl Key for DiskLatencyHistogramKey {
+        fn get_label_keys() -> Vec<String> {
+            vec![
+                "major".to_string(),
+                "minor".to_string(),
+                "operation".to_string(),
+            ]
+        }
+
+        fn get_label_values(&self) -> Vec<String> {
+            let operation = match operation(self.op) {
+                Some(name) => name.to_string(),
+                None => format!("op_{}", self.op),
+            };
+            vec![self.major.to_string(), self.minor.to_string(), operation]
+        }
+    }
+
+    unsafe impl aya::Pod for DiskHistogramKey {}
+    impl Key for DiskHistogramKey {
+        fn get_label_keys() -> Vec<String> {
+            vec!["major".to_string(), "minor".to_string()]
+        }
+
+        fn get_label_values(&self) -> Vec<String> {
+            vec![self.major.to_string(), self.minor.to_string()]
+        }
+    }
+
+    unsafe impl aya::Pod for RequestTrackerEntry {}
+}
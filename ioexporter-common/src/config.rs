@@ -0,0 +1,7 @@
+/// `Array<u32>` of runtime options set by userspace, indexed by the `*_CONFIG_IDX`
+/// constants. Non zero means enabled.
+pub const CONFIG_MAP: &str = "CONFIG";
+
+pub const PROCESS_ATTRIBUTION_CONFIG_IDX: u32 = 0;
+pub const CGROUP_ATTRIBUTION_CONFIG_IDX: u32 = 1;
+pub const NVME_QUEUE_LABELS_CONFIG_IDX: u32 = 2;
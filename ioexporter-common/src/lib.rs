@@ -1 +1,7 @@
-#![no_std]
+#![cfg_attr(not(feature = "user"), no_std)]
+
+pub mod attribution;
+pub mod block;
+pub mod config;
+pub mod nvme;
+pub mod pagecache;
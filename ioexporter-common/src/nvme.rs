@@ -0,0 +1,282 @@
+use core::mem::{offset_of, size_of};
+
+use crate::attribution::ProcessKey;
+
+/// `LruHashMap<NvmeTrackerKey, NvmeTrackerEntry>` of the commands set up and not
+/// completed yet
+pub const STATE_TRACKER_MAP: &str = "STATE_TRACKER";
+/// Histogram of IO command latency keyed by `NvmeHistogramKey`
+pub const NVME_HISTOGRAM_MAP: &str = "NVME_HISTOGRAM";
+/// Histogram of admin command latency keyed by `NvmeAdminHistogramKey`
+pub const NVME_ADMIN_HISTOGRAM_MAP: &str = "NVME_ADMIN_HISTOGRAM";
+/// `PerCpuHashMap<NvmeCompletionKey, u64>` of completed commands
+pub const NVME_COMPLETIONS_MAP: &str = "NVME_COMPLETIONS";
+/// `PerCpuArray<u64>` of NVMe counters, indexed by the `*_COUNTER_IDX` constants
+pub const NVME_METRICS_MAP: &str = "NVME_METRICS";
+/// `HashMap<[u8; DISK_NAME_LEN], u32>` of the logical block size of each disk,
+/// maintained by userspace
+pub const NVME_BLOCK_SIZES_MAP: &str = "NVME_BLOCK_SIZES";
+
+/// Commands set up while a command with the same key was still tracked
+pub const TRACKER_OVERWRITES_COUNTER_IDX: u32 = 0;
+/// Commands completed without being tracked, e.g. evicted from the tracker
+pub const TRACKER_MISSES_COUNTER_IDX: u32 = 1;
+
+/// Length of the `disk` field of the nvme tracepoints
+pub const DISK_NAME_LEN: usize = 32;
+
+/// Command ids are only unique within a submission queue of a controller.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[repr(C)]
+pub struct NvmeTrackerKey {
+    pub ctrl_id: i32,
+    pub qid: i32,
+    pub cid: u16,
+    pub pad1: u16,
+}
+
+const _: () = assert!(size_of::<NvmeTrackerKey>() == 12);
+const _: () = assert!(offset_of!(NvmeTrackerKey, cid) == 8);
+
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct NvmeTrackerEntry {
+    /// Timestamp of nvme_setup_cmd
+    pub from: u64,
+    pub opcode: u8,
+    /// 1 when process is the task that submitted the command
+    pub attributed: u8,
+    pub pad2: u16,
+    pub process: ProcessKey,
+    /// Namespace of the command, 0 when queue labels are disabled
+    pub nsid: u32,
+    /// Data transferred by read, write and compare commands, 0 for the others or
+    /// when the command is not attributed
+    pub bytes: u32,
+    /// So that userspace can tell which disk a stuck command is on
+    pub disk: [u8; DISK_NAME_LEN],
+}
+
+const _: () = assert!(size_of::<NvmeTrackerEntry>() == 72);
+const _: () = assert!(offset_of!(NvmeTrackerEntry, process) == 12);
+const _: () = assert!(offset_of!(NvmeTrackerEntry, nsid) == 32);
+const _: () = assert!(offset_of!(NvmeTrackerEntry, disk) == 40);
+
+/// Key of `NVME_HISTOGRAM`, for the IO commands only: the admin queue (qid 0) goes
+/// to `NVME_ADMIN_HISTOGRAM`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[repr(C)]
+pub struct NvmeHistogramKey {
+    /// NUL terminated
+    pub disk: [u8; DISK_NAME_LEN],
+    pub opcode: u8,
+    pub pad1: u8,
+    pub pad2: u16,
+    /// Namespace and submission queue, 0 when queue labels are disabled
+    pub nsid: u32,
+    pub qid: u32,
+}
+
+const _: () = assert!(size_of::<NvmeHistogramKey>() == 44);
+const _: () = assert!(offset_of!(NvmeHistogramKey, opcode) == 32);
+const _: () = assert!(offset_of!(NvmeHistogramKey, nsid) == 36);
+const _: () = assert!(offset_of!(NvmeHistogramKey, qid) == 40);
+
+/// Key of `NVME_ADMIN_HISTOGRAM`. Admin commands are not bound to a namespace, so
+/// they are keyed by controller rather than disk.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[repr(C)]
+pub struct NvmeAdminHistogramKey {
+    pub ctrl_id: i32,
+    pub opcode: u8,
+    pub pad1: u8,
+    pub pad2: u16,
+}
+
+const _: () = assert!(size_of::<NvmeAdminHistogramKey>() == 8);
+const _: () = assert!(offset_of!(NvmeAdminHistogramKey, opcode) == 4);
+
+/// Key of `NVME_COMPLETIONS`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[repr(C)]
+pub struct NvmeCompletionKey {
+    pub disk: [u8; DISK_NAME_LEN],
+    pub opcode: u8,
+    pub pad1: u8,
+    /// Status code type (bits 8-10) and status code (bits 0-7)
+    pub status: u16,
+}
+
+const _: () = assert!(size_of::<NvmeCompletionKey>() == 36);
+const _: () = assert!(offset_of!(NvmeCompletionKey, opcode) == 32);
+const _: () = assert!(offset_of!(NvmeCompletionKey, status) == 34);
+
+#[cfg(feature = "user")]
+mod user {
+    use ebpf_histogram::Key;
+    use phf::phf_map;
+
+    use super::*;
+
+    // https://elixir.bootlin.com/linux/latest/source/include/linux/nvme.h
+    static OP_CODE: phf::Map<u8, &'static str> = phf_map! {
+        0x00u8 => "nvme_cmd_flush",
+        0x01u8 => "nvme_cmd_write",
+        0x02u8 => "nvme_cmd_read",
+        0x04u8 => "nvme_cmd_write_uncor",
+        0x05u8 => "nvme_cmd_compare",
+        0x08u8 => "nvme_cmd_write_zeroes",
+        0x09u8 => "nvme_cmd_dsm",
+        0x0cu8 => "nvme_cmd_verify",
+        0x0du8 => "nvme_cmd_resv_register",
+        0x0eu8 => "nvme_cmd_resv_report",
+        0x11u8 => "nvme_cmd_resv_acquire",
+        0x12u8 => "nvme_cmd_io_mgmt_recv",
+        0x15u8 => "nvme_cmd_resv_release",
+        0x19u8 => "nvme_cmd_copy",
+        0x1du8 => "nvme_cmd_io_mgmt_send",
+        0x79u8 => "nvme_cmd_zone_mgmt_send",
+        0x7au8 => "nvme_cmd_zone_mgmt_recv",
+        0x7du8 => "nvme_cmd_zone_append",
+    };
+
+    static ADMIN_OP_CODE: phf::Map<u8, &'static str> = phf_map! {
+        0x00u8 => "nvme_admin_delete_sq",
+        0x01u8 => "nvme_admin_create_sq",
+        0x02u8 => "nvme_admin_get_log_page",
+        0x04u8 => "nvme_admin_delete_cq",
+        0x05u8 => "nvme_admin_create_cq",
+        0x06u8 => "nvme_admin_identify",
+        0x08u8 => "nvme_admin_abort_cmd",
+        0x09u8 => "nvme_admin_set_features",
+        0x0au8 => "nvme_admin_get_features",
+        0x0cu8 => "nvme_admin_async_event",
+        0x0du8 => "nvme_admin_ns_mgmt",
+        0x10u8 => "nvme_admin_activate_fw",
+        0x11u8 => "nvme_admin_download_fw",
+        0x14u8 => "nvme_admin_dev_self_test",
+        0x15u8 => "nvme_admin_ns_attach",
+        0x18u8 => "nvme_admin_keep_alive",
+        0x19u8 => "nvme_admin_directive_send",
+        0x1au8 => "nvme_admin_directive_recv",
+        0x1cu8 => "nvme_admin_virtual_mgmt",
+        0x1du8 => "nvme_admin_nvme_mi_send",
+        0x1eu8 => "nvme_admin_nvme_mi_recv",
+        0x7cu8 => "nvme_admin_dbbuf",
+        0x7fu8 => "nvme_fabrics_command",
+        0x80u8 => "nvme_admin_format_nvm",
+        0x81u8 => "nvme_admin_security_send",
+        0x82u8 => "nvme_admin_security_recv",
+        0x84u8 => "nvme_admin_sanitize_nvm",
+        0x86u8 => "nvme_admin_get_lba_status",
+    };
+
+    /// Disk name up to the first NUL.
+    pub fn disk_name(disk: &[u8; DISK_NAME_LEN]) -> String {
+        let len = disk.iter().position(|c| *c == 0).unwrap_or(disk.len());
+        String::from_utf8_lossy(&disk[..len]).to_string()
+    }
+
+    /// Name of an NVMe opcode, `opcode_0x..` for vendor specific or unknown ones.
+    /// Admin commands are not bound to a namespace, so the tracepoints have no disk.
+    pub fn operation(disk: &[u8; DISK_NAME_LEN], opcode: u8) -> String {
+        if disk[0] == 0 {
+            return admin_operation(opcode);
+        }
+        io_operation(opcode)
+    }
+
+    fn io_operation(opcode: u8) -> String {
+        name(&OP_CODE, opcode)
+    }
+
+    /// Name of an NVMe admin opcode, `opcode_0x..` for vendor specific or unknown ones.
+    pub fn admin_operation(opcode: u8) -> String {
+        name(&ADMIN_OP_CODE, opcode)
+    }
+
+    fn name(table: &phf::Map<u8, &'static str>, opcode: u8) -> String {
+        match table.get(&opcode) {
+            Some(name) => name.to_string(),
+            None => format!("opcode_{:#04x}", opcode),
+        }
+    }
+
+    unsafe impl aya::Pod for NvmeHistogramKey {}
+    impl Key for NvmeHistogramKey {
+        fn get_label_keys() -> Vec<String> {
+            vec![
+                "disk".to_string(),
+                "operation".to_string(),
+                "nsid".to_string(),
+                "qid".to_string(),
+            ]
+        }
+
+        fn get_label_values(&self) -> Vec<String> {
+            vec![
+                disk_name(&self.disk),
+                io_operation(self.opcode),
+                self.nsid.to_string(),
+                self.qid.to_string(),
+            ]
+        }
+    }
+
+    unsafe impl aya::Pod for NvmeAdminHistogramKey {}
+    impl Key for NvmeAdminHistogramKey {
+        fn get_label_keys() -> Vec<String> {
+            vec!["controller".to_string(), "operation".to_string()]
+        }
+
+        fn get_label_values(&self) -> Vec<String> {
+            vec![
+                format!("nvme{}", self.ctrl_id),
+                admin_operation(self.opcode),
+            ]
+        }
+    }
+
+    unsafe impl aya::Pod for NvmeCompletionKey {}
+    unsafe impl aya::Pod for NvmeTrackerKey {}
+    unsafe impl aya::Pod for NvmeTrackerEntry {}
+}
+
+#[cfg(feature = "user")]
+pub use user::{admin_operation, disk_name, operation};
+
+#[cfg(all(test, feature = "user"))]
+mod tests {
+    use super::*;
+
+    fn disk(name: &str) -> [u8; DISK_NAME_LEN] {
+        let mut disk = [0; DISK_NAME_LEN];
+        disk[..name.len()].copy_from_slice(name.as_bytes());
+        disk
+    }
+
+    #[test]
+    fn names_io_opcodes() {
+        let disk = disk("nvme0n1");
+        assert_eq!(operation(&disk, 0x02), "nvme_cmd_read");
+        assert_eq!(operation(&disk, 0x7d), "nvme_cmd_zone_append");
+        // Admin opcodes overlap the IO ones
+        assert_eq!(operation(&disk, 0x81), "opcode_0x81");
+        assert_eq!(operation(&disk, 0x03), "opcode_0x03");
+    }
+
+    #[test]
+    fn names_admin_opcodes_without_disk() {
+        let disk = disk("");
+        assert_eq!(operation(&disk, 0x06), "nvme_admin_identify");
+        assert_eq!(operation(&disk, 0x81), "nvme_admin_security_send");
+        assert_eq!(operation(&disk, 0x7d), "opcode_0x7d");
+        assert_eq!(admin_operation(0xc0), "opcode_0xc0");
+    }
+
+    #[test]
+    fn trims_the_disk_name() {
+        assert_eq!(disk_name(&disk("nvme0n1")), "nvme0n1");
+        assert_eq!(disk_name(&disk("")), "");
+    }
+}
@@ -0,0 +1,6 @@
+/// `PerCpuArray<u64>` of page cache counters, indexed by the `*_COUNTER_IDX` constants
+pub const PAGE_CACHE_METRICS_MAP: &str = "PAGE_CACHE_METRICS";
+
+pub const MARK_PAGE_ACCESSED_COUNTER_IDX: u32 = 0;
+pub const ADD_TO_PAGE_LRU_COUNTER_IDX: u32 = 1;
+pub const MARK_BUFFER_DIRTY_COUNTER_IDX: u32 = 2;
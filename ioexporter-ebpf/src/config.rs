@@ -0,0 +1,13 @@
+use aya_ebpf::{macros::map, maps::Array};
+
+// Runtime options set by userspace, indexed by the *_CONFIG_IDX constants of
+// ioexporter_common::config. Non zero means enabled.
+#[map]
+static CONFIG: Array<u32> = Array::with_max_entries(8, 0);
+
+pub fn enabled(idx: u32) -> bool {
+    match CONFIG.get(idx) {
+        Some(value) => *value != 0,
+        None => false,
+    }
+}
@@ -3,26 +3,161 @@
 #[allow(non_camel_case_types)]
 
 
-use aya_ebpf::{macros::{map, btf_tracepoint}, programs::BtfTracePointContext, helpers::bpf_ktime_get_ns};
+use core::sync::atomic::{AtomicI64, Ordering};
+
+use aya_ebpf::{macros::{map, btf_tracepoint}, programs::BtfTracePointContext, helpers::{bpf_get_current_cgroup_id, bpf_ktime_get_ns}, maps::{HashMap, LruHashMap, LruPerCpuHashMap}, bindings::BPF_NOEXIST};
 use ebpf_histogram_ebpf::BpfHistogram;
 
+use ioexporter_common::attribution::{IoStats, ProcessKey};
+use ioexporter_common::block::{DiskHistogramKey, DiskLatencyHistogramKey, RequestTrackerEntry};
+use ioexporter_common::config::{CGROUP_ATTRIBUTION_CONFIG_IDX, PROCESS_ATTRIBUTION_CONFIG_IDX};
+
+use crate::config;
+use crate::process;
+use crate::stats;
 use crate::vmlinux;
 
 
-#[derive(Copy, Clone)]
-#[repr(C)]
-pub struct DiskLatencyHistogramKey {
-    pub major: i32,
-    pub minor: i32
-}
+// In flight requests, keyed by request pointer. Evicted entries stay counted in
+// BLOCK_INFLIGHT until the block probes are reattached, keep room for every tag
+#[map]
+static RQ_TRACKER: LruHashMap<u64, RequestTrackerEntry> = LruHashMap::with_max_entries(65536, 0);
 
+// Total latency: insert (or issue when the scheduler is bypassed) -> complete
 #[map]
 static BLOCK_HISTOGRAM: BpfHistogram<DiskLatencyHistogramKey> = BpfHistogram::with_max_entries(1000, 0);
 
+// Time spent in the scheduler queue: insert -> issue
+#[map]
+static BLOCK_QUEUE_HISTOGRAM: BpfHistogram<DiskLatencyHistogramKey> = BpfHistogram::with_max_entries(1000, 0);
+
+// Device service time: issue -> complete
+#[map]
+static BLOCK_SERVICE_HISTOGRAM: BpfHistogram<DiskLatencyHistogramKey> = BpfHistogram::with_max_entries(1000, 0);
+
+// Per process requests, bytes and latency, when process attribution is enabled
+#[map]
+static PROCESS_BLOCK_STATS: LruPerCpuHashMap<ProcessKey, IoStats> = LruPerCpuHashMap::with_max_entries(10240, 0);
+
+// Per cgroup requests, bytes and latency, keyed by cgroup id, when cgroup attribution is enabled
+#[map]
+static CGROUP_BLOCK_STATS: LruPerCpuHashMap<u64, IoStats> = LruPerCpuHashMap::with_max_entries(10240, 0);
+
+// Request size in bytes
+#[map]
+static BLOCK_SIZE_HISTOGRAM: BpfHistogram<DiskLatencyHistogramKey> = BpfHistogram::with_max_entries(1000, 0);
+
+// Number of requests in flight (inserted or issued, not completed) per device
+#[map]
+static BLOCK_INFLIGHT: HashMap<DiskHistogramKey, i64> = HashMap::with_max_entries(1000, 0);
+
+// Requests in flight on the device, sampled each time a request is issued
+#[map]
+static BLOCK_QUEUE_DEPTH_HISTOGRAM: BpfHistogram<DiskHistogramKey> = BpfHistogram::with_max_entries(1000, 0);
+
 // https://elixir.bootlin.com/linux/latest/source/include/linux/blk_types.h#L354
 static REQ_OP_BITS: u32 = 8;
 static REQ_OP_MASK: u32 = (1 << REQ_OP_BITS) - 1;
 
+// The operation labels are looked up with the values shared through ioexporter-common
+const _: () = {
+    use ioexporter_common::block::req_op as common;
+    use vmlinux::req_op as kernel;
+    assert!(common::REQ_OP_READ as u32 == kernel::REQ_OP_READ);
+    assert!(common::REQ_OP_WRITE as u32 == kernel::REQ_OP_WRITE);
+    assert!(common::REQ_OP_FLUSH as u32 == kernel::REQ_OP_FLUSH);
+    assert!(common::REQ_OP_DISCARD as u32 == kernel::REQ_OP_DISCARD);
+    assert!(common::REQ_OP_SECURE_ERASE as u32 == kernel::REQ_OP_SECURE_ERASE);
+    assert!(common::REQ_OP_WRITE_ZEROES as u32 == kernel::REQ_OP_WRITE_ZEROES);
+    assert!(common::REQ_OP_ZONE_OPEN as u32 == kernel::REQ_OP_ZONE_OPEN);
+    assert!(common::REQ_OP_ZONE_CLOSE as u32 == kernel::REQ_OP_ZONE_CLOSE);
+    assert!(common::REQ_OP_ZONE_FINISH as u32 == kernel::REQ_OP_ZONE_FINISH);
+    assert!(common::REQ_OP_ZONE_APPEND as u32 == kernel::REQ_OP_ZONE_APPEND);
+    assert!(common::REQ_OP_ZONE_RESET as u32 == kernel::REQ_OP_ZONE_RESET);
+    assert!(common::REQ_OP_ZONE_RESET_ALL as u32 == kernel::REQ_OP_ZONE_RESET_ALL);
+    assert!(common::REQ_OP_DRV_IN as u32 == kernel::REQ_OP_DRV_IN);
+    assert!(common::REQ_OP_DRV_OUT as u32 == kernel::REQ_OP_DRV_OUT);
+};
+
+// https://elixir.bootlin.com/linux/latest/source/include/linux/kdev_t.h
+static MINORBITS: u32 = 20;
+static MINORMASK: u32 = (1 << MINORBITS) - 1;
+
+// Device number of the partition the request targets, or of the whole disk
+// when the request carries no partition (e.g. passthrough requests).
+unsafe fn request_dev(req: *const vmlinux::request) -> (i32, i32) {
+    let part = (*req).part;
+    if !part.is_null() {
+        let dev = (*part).bd_dev;
+        return ((dev >> MINORBITS) as i32, (dev & MINORMASK) as i32)
+    }
+    let disk = (*(*req).q).disk;
+    ((*disk).major, (*disk).first_minor)
+}
+
+// Add delta to the in flight counter of the device and return the new value.
+// The increment is atomic, the returned value is only as accurate as a sample.
+unsafe fn inflight_add(key: &DiskHistogramKey, delta: i64) -> i64 {
+    if BLOCK_INFLIGHT.get_ptr_mut(key).is_none() {
+        // Fails harmlessly if another CPU created it in the meantime
+        let _ = BLOCK_INFLIGHT.insert(key, &0, BPF_NOEXIST as u64);
+    }
+    match BLOCK_INFLIGHT.get_ptr_mut(key) {
+        Some(ptr) => {
+            AtomicI64::from_ptr(ptr).fetch_add(delta, Ordering::Relaxed);
+            *ptr
+        }
+        None => 0,
+    }
+}
+
+// Entry of the request, or a new one if it was never seen. Request structs are
+// preallocated and reused: an entry with another start time was left behind by an
+// earlier request (e.g. one freed without completing) and must not be used.
+// The start time is only stamped when the kernel needs it (0 otherwise), without it
+// `stale` tells from the entry alone whether it belongs to an earlier request.
+unsafe fn lookup(
+    req: *const vmlinux::request,
+    stale: impl Fn(&RequestTrackerEntry) -> bool,
+) -> RequestTrackerEntry {
+    let start_time = (*req).start_time_ns;
+    match RQ_TRACKER.get(&(req as u64)) {
+        Some(entry) if entry.start_time == start_time && (start_time != 0 || !stale(entry)) => *entry,
+        Some(entry) => {
+            release(entry);
+            RequestTrackerEntry{ start_time, ..RequestTrackerEntry::EMPTY }
+        }
+        None => RequestTrackerEntry{ start_time, ..RequestTrackerEntry::EMPTY },
+    }
+}
+
+// Remove the request from BLOCK_INFLIGHT once it is done with, if it was counted
+unsafe fn release(entry: &RequestTrackerEntry) {
+    if entry.counted != 0 {
+        inflight_add(&entry.device, -1);
+    }
+}
+
+// Account the request in BLOCK_INFLIGHT and attribute it to the current task and its
+// cgroup the first time it is seen, and return the number of requests in flight on the device
+unsafe fn track(key: &DiskHistogramKey, entry: &mut RequestTrackerEntry) -> i64 {
+    if entry.counted != 0 {
+        return inflight_add(key, 0)
+    }
+    entry.counted = 1;
+    entry.device = *key;
+    if config::enabled(PROCESS_ATTRIBUTION_CONFIG_IDX) {
+        entry.process = process::current();
+        entry.attributed = 1;
+    }
+    // The cgroup of the task, not the blkcg of the bio: writeback flushed by
+    // kworkers is accounted to the root cgroup
+    if config::enabled(CGROUP_ATTRIBUTION_CONFIG_IDX) {
+        entry.cgroup = bpf_get_current_cgroup_id();
+    }
+    inflight_add(key, 1)
+}
+
 
 #[btf_tracepoint(function="block_rq_insert")]
 pub fn block_rq_insert(ctx: BtfTracePointContext) -> u32 {
@@ -30,10 +165,53 @@ pub fn block_rq_insert(ctx: BtfTracePointContext) -> u32 {
     // info!(&ctx, "rq insert {}", req as usize);
 
     unsafe {
-        let disk: vmlinux::gendisk = *((*(*req).q).disk);
-        // info!(&ctx, "insert disk {}.{}", disk.major, disk.minors);
-        
-        // RQ_TRACKER.insert(&ptr, &0, 0).unwrap();
+        let timestamp = bpf_ktime_get_ns();
+        // A requeued request is inserted again, start over but keep it counted once.
+        // Without a start time, it can't be told apart from a new request reusing
+        // the struct: start over from scratch.
+        let mut entry = lookup(req, |_| true);
+        entry.inserted = timestamp;
+        entry.issued = 0;
+        let (major, minor) = request_dev(req);
+        track(&DiskHistogramKey{ major, minor }, &mut entry);
+        let _ = RQ_TRACKER.insert(&(req as u64), &entry, 0);
+    }
+    return 0
+}
+
+// Fires for every request dispatched to the driver, including the ones
+// that never went through the scheduler (e.g. direct issue with `none`)
+#[btf_tracepoint(function="block_rq_issue")]
+pub fn block_rq_issue(ctx: BtfTracePointContext) -> u32 {
+    let req: *const vmlinux::request = unsafe { ctx.arg(0) };
+
+    unsafe {
+        let timestamp = bpf_ktime_get_ns();
+        // A request is issued once per insert, an already issued entry without a
+        // start time was left behind by an earlier request
+        let mut entry = lookup(req, |entry| entry.issued != 0);
+        entry.issued = timestamp;
+        // Partial completions shrink __data_len, keep the size of the whole request
+        entry.bytes = (*req).__data_len;
+        let (major, minor) = request_dev(req);
+        let key = DiskHistogramKey{ major, minor };
+        let depth = track(&key, &mut entry);
+        BLOCK_QUEUE_DEPTH_HISTOGRAM.observe(key, depth.max(0) as u64);
+        let _ = RQ_TRACKER.insert(&(req as u64), &entry, 0);
+    }
+    return 0
+}
+
+// Fires for a request merged into another one: it is freed without completing
+#[btf_tracepoint(function="block_rq_merge")]
+pub fn block_rq_merge(ctx: BtfTracePointContext) -> u32 {
+    let req: *const vmlinux::request = unsafe { ctx.arg(0) };
+
+    unsafe {
+        if let Some(entry) = RQ_TRACKER.get(&(req as u64)) {
+            release(entry);
+        }
+        let _ = RQ_TRACKER.remove(&(req as u64));
     }
     return 0
 }
@@ -41,14 +219,48 @@ pub fn block_rq_insert(ctx: BtfTracePointContext) -> u32 {
 #[btf_tracepoint(function="block_rq_complete")]
 pub fn block_rq_complete(ctx: BtfTracePointContext) -> u32 {
     let req: *const vmlinux::request = unsafe { ctx.arg(0) };
+    let nr_bytes: u32 = unsafe { ctx.arg(2) };
 
     unsafe {
+        // Fired by blk_update_request for each part of a partially completed request,
+        // before __data_len is decreased: only the last part completes the request
+        if nr_bytes < (*req).__data_len {
+            return 0
+        }
         let timestamp = bpf_ktime_get_ns();
-        let disk: vmlinux::gendisk = *((*(*req).q).disk);
-        let latency = timestamp - (*req).io_start_time_ns;
-        // let flags = (*req).cmd_flags & REQ_OP_MASK;
-        BLOCK_HISTOGRAM.observe(DiskLatencyHistogramKey{ major: disk.major, minor: disk.minors }, latency);
-        // info!(&ctx, "complete disk {}.{} -> Latency: {}us, (flags: {})", disk.major,disk.minors, latency / 1000, flags);
+        let (major, minor) = request_dev(req);
+        let op = ((*req).cmd_flags & REQ_OP_MASK) as u8;
+        let key = DiskLatencyHistogramKey{ major, minor, op, pad1: 0, pad2: 0 };
+
+        let entry = lookup(req, |_| false);
+        let _ = RQ_TRACKER.remove(&(req as u64));
+        // Requests issued before the program was attached only have their remaining size
+        let bytes = if entry.bytes != 0 { entry.bytes } else { (*req).__data_len } as u64;
+        BLOCK_SIZE_HISTOGRAM.observe(key, bytes);
+        release(&entry);
+        // Requests issued before the program was attached are only known by
+        // io_start_time_ns, which is not set when queue stats are disabled
+        let issued = if entry.issued != 0 { entry.issued } else { (*req).io_start_time_ns };
+        // Requests that bypass the scheduler are never inserted
+        let inserted = entry.inserted;
+
+        if issued != 0 {
+            BLOCK_SERVICE_HISTOGRAM.observe(key, timestamp - issued);
+        }
+        if inserted != 0 && issued >= inserted {
+            BLOCK_QUEUE_HISTOGRAM.observe(key, issued - inserted);
+        }
+        let start = if inserted != 0 { inserted } else { issued };
+        if start != 0 {
+            BLOCK_HISTOGRAM.observe(key, timestamp - start);
+            if entry.attributed != 0 {
+                stats::account(&PROCESS_BLOCK_STATS, &entry.process, bytes, timestamp - start);
+            }
+            if entry.cgroup != 0 {
+                stats::account(&CGROUP_BLOCK_STATS, &entry.cgroup, bytes, timestamp - start);
+            }
+        }
+        // info!(&ctx, "complete disk {}.{} -> Latency: {}us, (op: {})", major, minor, (timestamp - issued) / 1000, op);
     }
     return 0
 }
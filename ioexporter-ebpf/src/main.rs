@@ -7,6 +7,9 @@
 
 
 mod vmlinux;
+mod config;
+mod stats;
+mod process;
 mod pagecache;
 mod iolatency;
 mod nvmelatency;
@@ -6,34 +6,77 @@ use aya_ebpf::{
     helpers,
     macros::{map, tracepoint},
     programs::TracePointContext,
-    maps::LruHashMap,
+    maps::{HashMap, LruHashMap, LruPerCpuHashMap, PerCpuArray, PerCpuHashMap},
+    bindings::BPF_NOEXIST,
 };
 
-#[repr(C)]
-pub struct NvmeTrackerEntry {
-    pub from: u64,
-    pub opcode: u8,
-    pub pad1: u8,
-    pub pad2: u16,
-    pub pad3: u32
-}
+use ioexporter_common::attribution::{IoStats, ProcessKey};
+use ioexporter_common::config::{NVME_QUEUE_LABELS_CONFIG_IDX, PROCESS_ATTRIBUTION_CONFIG_IDX};
+use ioexporter_common::nvme::{
+    NvmeAdminHistogramKey, NvmeCompletionKey, NvmeHistogramKey, NvmeTrackerEntry, NvmeTrackerKey,
+    DISK_NAME_LEN, TRACKER_MISSES_COUNTER_IDX, TRACKER_OVERWRITES_COUNTER_IDX,
+};
 
-#[repr(C)]
-pub struct NvneHistogramKey {
-     // In practice, 31 first bytes are the disk and the last is opcode
-    // This is done because of adding a mere u8 would require adding 32 bytes to keep alignement 
-    pub opaque: [u8; 32],
-}
+use crate::config;
+use crate::process;
+use crate::stats;
 
 use aya_log_ebpf::info;
 use ebpf_histogram_ebpf::BpfHistogram;
 
+// Commands in flight, up to 64K: e.g. 64 queues with a depth of 1024
+#[map]
+static STATE_TRACKER: LruHashMap<NvmeTrackerKey, NvmeTrackerEntry> = LruHashMap::with_max_entries(65536, 0);
+
 #[map]
-static STATE_TRACKER: LruHashMap<u16, NvmeTrackerEntry> = LruHashMap::with_max_entries(1000, 0);
+static NVME_METRICS: PerCpuArray<u64> = PerCpuArray::with_max_entries(4, 0);
+
 
+#[map]
+static NVME_HISTOGRAM: BpfHistogram<NvmeHistogramKey> = BpfHistogram::with_max_entries(1000, 0);
 
+// Commands of the admin queue (qid 0), kept apart so that they don't skew the IO latency
 #[map]
-static NVME_HISTOGRAM: BpfHistogram<NvneHistogramKey> = BpfHistogram::with_max_entries(1000, 0);
+static NVME_ADMIN_HISTOGRAM: BpfHistogram<NvmeAdminHistogramKey> = BpfHistogram::with_max_entries(1000, 0);
+
+// Completed commands per disk, opcode and status
+#[map]
+static NVME_COMPLETIONS: PerCpuHashMap<NvmeCompletionKey, u64> = PerCpuHashMap::with_max_entries(1000, 0);
+
+// Per process commands, bytes and latency, when process attribution is enabled
+#[map]
+static PROCESS_NVME_STATS: LruPerCpuHashMap<ProcessKey, IoStats> = LruPerCpuHashMap::with_max_entries(10240, 0);
+
+// Logical block size per disk, written by userspace from sysfs
+#[map]
+static NVME_BLOCK_SIZES: HashMap<[u8; DISK_NAME_LEN], u32> = HashMap::with_max_entries(1024, 0);
+
+// https://elixir.bootlin.com/linux/latest/source/include/linux/nvme.h
+const NVME_CMD_WRITE: u8 = 0x01;
+const NVME_CMD_READ: u8 = 0x02;
+const NVME_CMD_COMPARE: u8 = 0x05;
+
+// Bytes transferred by an IO command, from its number of logical blocks (0's based,
+// in the low 16 bits of cdw12) and the block size of the disk
+unsafe fn command_bytes(ctx: &TracePointContext, disk: &[u8; DISK_NAME_LEN], qid: i32, opcode: u8) -> u32 {
+    // Admin commands reuse the opcodes
+    if qid == 0 {
+        return 0
+    }
+    if opcode != NVME_CMD_WRITE && opcode != NVME_CMD_READ && opcode != NVME_CMD_COMPARE {
+        return 0
+    }
+    // cdw10[24] starts at 61, cdw12 is its third dword
+    const CDW12_OFFSET: usize = 61 + 8;
+    let nlb: u16 = match ctx.read_at(CDW12_OFFSET) {
+        Ok(nlb) => nlb,
+        Err(_) => return 0,
+    };
+    match NVME_BLOCK_SIZES.get(disk) {
+        Some(size) => (nlb as u32 + 1) * *size,
+        None => 0,
+    }
+}
 
 
 
@@ -67,17 +110,40 @@ pub fn nvme_setup_cmd(ctx: TracePointContext) -> c_long {
 
 pub fn try_nvme_setup_cmd(ctx: TracePointContext) -> Result<c_long, c_long> {
     // sudo cat /sys/kernel/debug/tracing/events/nvme/nvme_setup_cmd/format
+    const CTRL_ID_OFFSET: usize = 40;
+    const QID_OFFSET: usize = 44;
     const CID_OFFSET: usize = 52;
     const OPCODE_OFFSET: usize = 48;
+    const NSID_OFFSET: usize = 56;
+    const DISK_OFFSET: usize = 8;
+    let disk: [u8; DISK_NAME_LEN] = unsafe { ctx.read_at(DISK_OFFSET)? };
+    let ctrl_id: i32 = unsafe { ctx.read_at(CTRL_ID_OFFSET)? };
+    let qid: i32 = unsafe { ctx.read_at(QID_OFFSET)? };
     let opcode: u8 = unsafe { ctx.read_at(OPCODE_OFFSET)? };
     let cid: u16 = unsafe { ctx.read_at(CID_OFFSET)? };
+    let nsid: u32 = unsafe { ctx.read_at(NSID_OFFSET)? };
+    let key = NvmeTrackerKey{ ctrl_id, qid, cid, pad1: 0 };
 
     info!(&ctx, "nvme start cid {}", cid);
     unsafe {
         let from = helpers::bpf_ktime_get_ns();
         // TODO find a better way to pad
-        let entry = NvmeTrackerEntry{ from, opcode, pad1: 0, pad2: 0, pad3: 0};
-        STATE_TRACKER.insert(&cid, &entry, 0)?;
+        let mut entry = NvmeTrackerEntry{ from, opcode, attributed: 0, pad2: 0, process: ProcessKey::EMPTY, nsid: 0, bytes: 0, disk };
+        if config::enabled(NVME_QUEUE_LABELS_CONFIG_IDX) {
+            entry.nsid = nsid;
+        }
+        if config::enabled(PROCESS_ATTRIBUTION_CONFIG_IDX) {
+            entry.process = process::current();
+            entry.attributed = 1;
+            entry.bytes = command_bytes(&ctx, &disk, qid, opcode);
+        }
+        if STATE_TRACKER.insert(&key, &entry, BPF_NOEXIST as u64).is_err() {
+            // The previous command never completed (or its completion was missed)
+            if let Some(metric) = NVME_METRICS.get_ptr_mut(TRACKER_OVERWRITES_COUNTER_IDX) {
+                *metric += 1;
+            }
+            STATE_TRACKER.insert(&key, &entry, 0)?;
+        }
 
         info!(&ctx, "nvme disk {}:{}:{}", entry.from, cid, entry.opcode);
     }
@@ -95,24 +161,68 @@ pub fn nvme_complete_rq(ctx: TracePointContext) -> c_long {
 
 
 
+// name: nvme_complete_rq
+// format:
+//         field:unsigned short common_type;       offset:0;       size:2; signed:0;
+//         field:unsigned char common_flags;       offset:2;       size:1; signed:0;
+//         field:unsigned char common_preempt_count;       offset:3;       size:1; signed:0;
+//         field:int common_pid;   offset:4;       size:4; signed:1;
+
+//         field:char disk[32];    offset:8;       size:32;        signed:1;
+//         field:int ctrl_id;      offset:40;      size:4; signed:1;
+//         field:int qid;  offset:44;      size:4; signed:1;
+//         field:int cid;  offset:48;      size:4; signed:1;
+//         field:u64 result;       offset:56;      size:8; signed:0;
+//         field:u8 retries;       offset:64;      size:1; signed:0;
+//         field:u8 flags; offset:65;      size:1; signed:0;
+//         field:u16 status;       offset:66;      size:2; signed:0;
+
 pub fn try_nvme_complete_rq(ctx: TracePointContext) -> Result<c_long, c_long> {
-    // sudo cat /sys/kernel/debug/tracing/events/nvme/nvme_setup_cmd/format
+    // sudo cat /sys/kernel/debug/tracing/events/nvme/nvme_complete_rq/format
     const DISK_OFFSET: usize = 8;
-    let mut opaque: [u8; 32] = unsafe { ctx.read_at(DISK_OFFSET)? };
+    let disk: [u8; DISK_NAME_LEN] = unsafe { ctx.read_at(DISK_OFFSET)? };
+    const CTRL_ID_OFFSET: usize = 40;
+    const QID_OFFSET: usize = 44;
     const CID_OFFSET: usize = 48;
+    let ctrl_id: i32 = unsafe { ctx.read_at(CTRL_ID_OFFSET)? };
+    let qid: i32 = unsafe { ctx.read_at(QID_OFFSET)? };
     let cid: u16 = unsafe { ctx.read_at(CID_OFFSET)? };
+    const STATUS_OFFSET: usize = 66;
+    // Drop the More and Do Not Retry bits, only keep the type and code
+    let status: u16 = unsafe { ctx.read_at::<u16>(STATUS_OFFSET)? } & 0x7ff;
+    let key = NvmeTrackerKey{ ctrl_id, qid, cid, pad1: 0 };
     unsafe {
         let now = &helpers::bpf_ktime_get_ns();
-        let entry = match STATE_TRACKER.get(&cid) {
-            Some(entry) => entry,
-            None => return Err(1),
+        let entry = match STATE_TRACKER.get(&key) {
+            Some(entry) => *entry,
+            None => {
+                // Set up before the program was attached, or evicted
+                if let Some(metric) = NVME_METRICS.get_ptr_mut(TRACKER_MISSES_COUNTER_IDX) {
+                    *metric += 1;
+                }
+                return Err(1)
+            }
         };
+        let _ = STATE_TRACKER.remove(&key);
         let from = entry.from;
         let opcode = entry.opcode;
         let elasped = now - from;
-        info!(&ctx, "nvme call finished for {}/{} elapsed {}us", cid, opcode, elasped / 1000);
-        opaque[31] = opcode;
-        let sub_key = NvneHistogramKey{ opaque };
+        if entry.attributed != 0 {
+            stats::account(&PROCESS_NVME_STATS, &entry.process, entry.bytes as u64, elasped);
+        }
+        info!(&ctx, "nvme call finished for {}:{}/{} elapsed {}us", qid, cid, opcode, elasped / 1000);
+        let completion = NvmeCompletionKey{ disk, opcode, pad1: 0, status };
+        match NVME_COMPLETIONS.get_ptr_mut(&completion) {
+            Some(count) => *count += 1,
+            None => { let _ = NVME_COMPLETIONS.insert(&completion, &1, 0); }
+        }
+        if qid == 0 {
+            NVME_ADMIN_HISTOGRAM.observe(NvmeAdminHistogramKey{ ctrl_id, opcode, pad1: 0, pad2: 0 }, elasped);
+            return Ok(0);
+        }
+        // Zeroed when queue labels are disabled so that all queues share one series
+        let qid = if config::enabled(NVME_QUEUE_LABELS_CONFIG_IDX) { qid as u32 } else { 0 };
+        let sub_key = NvmeHistogramKey{ disk, opcode, pad1: 0, pad2: 0, nsid: entry.nsid, qid };
         NVME_HISTOGRAM.observe(sub_key, elasped)
     }
 
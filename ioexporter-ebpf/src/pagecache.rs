@@ -4,15 +4,13 @@
 #[allow(dead_code)]
 
 use aya_ebpf::{macros::{kprobe, map}, programs::ProbeContext, maps::PerCpuArray};
+use ioexporter_common::pagecache::{ADD_TO_PAGE_LRU_COUNTER_IDX, MARK_BUFFER_DIRTY_COUNTER_IDX, MARK_PAGE_ACCESSED_COUNTER_IDX};
 
 
 #[map]
 static PAGE_CACHE_METRICS: PerCpuArray<u64> = PerCpuArray::with_max_entries(4, 0);
 
 
-static MARK_PAGE_ACCESSED_COUNTER_IDX: u32 = 0;
-static ADD_TO_PAGE_LRU_COUNTER_IDX: u32 = 1;
-static MARK_BUFFER_DIRTY_COUNTER_IDX: u32 = 2;
 
 #[kprobe]
 pub fn mark_page_accessed(_: ProbeContext) -> u32 {
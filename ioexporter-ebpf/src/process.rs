@@ -0,0 +1,10 @@
+use aya_ebpf::helpers::{bpf_get_current_comm, bpf_get_current_pid_tgid};
+use ioexporter_common::attribution::ProcessKey;
+
+// Task currently running. Only meaningful in the context of the submitter,
+// completions run in interrupt context.
+pub fn current() -> ProcessKey {
+    let tgid = (bpf_get_current_pid_tgid() >> 32) as u32;
+    let comm = bpf_get_current_comm().unwrap_or([0; 16]);
+    ProcessKey{ tgid, comm }
+}
@@ -0,0 +1,16 @@
+use aya_ebpf::maps::LruPerCpuHashMap;
+use ioexporter_common::attribution::IoStats;
+
+pub unsafe fn account<K>(map: &LruPerCpuHashMap<K, IoStats>, key: &K, bytes: u64, latency_ns: u64) {
+    match map.get_ptr_mut(key) {
+        Some(stats) => {
+            (*stats).requests += 1;
+            (*stats).bytes += bytes;
+            (*stats).latency_ns += latency_ns;
+        }
+        None => {
+            let stats = IoStats{ requests: 1, bytes, latency_ns };
+            let _ = map.insert(key, &stats, 0);
+        }
+    }
+}
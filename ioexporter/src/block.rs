@@ -0,0 +1,73 @@
+use std::sync::{Arc, Mutex};
+
+use aya::maps::{HashMap, MapData, MapError};
+use aya::Pod;
+use ioexporter_common::block::{DiskHistogramKey, RequestTrackerEntry};
+use log::warn;
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::{MetricFamily, MetricType};
+
+use crate::metrics::{family, new_desc};
+use crate::probes::Reset;
+
+pub type InflightMap = Arc<Mutex<HashMap<MapData, DiskHistogramKey, i64>>>;
+
+/// Exposes the `BLOCK_INFLIGHT` per device counters as the `io_disk_inflight` gauge.
+pub struct InflightCollector {
+    map: InflightMap,
+    desc: Desc,
+}
+
+impl InflightCollector {
+    pub fn new(map: InflightMap) -> Result<Self, prometheus::Error> {
+        let desc = new_desc(
+            "io_disk_inflight",
+            "Number of IO requests inserted or issued and not completed yet",
+            &["major", "minor"],
+        )?;
+        Ok(InflightCollector { map, desc })
+    }
+}
+
+impl Collector for InflightCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let map = self.map.lock().unwrap();
+        let values = map.iter().filter_map(|entry| match entry {
+            Ok((key, inflight)) => Some((
+                vec![key.major.to_string(), key.minor.to_string()],
+                inflight as f64,
+            )),
+            Err(e) => {
+                warn!("failed to read BLOCK_INFLIGHT: {}", e);
+                None
+            }
+        });
+        vec![family(&self.desc, MetricType::GAUGE, values)]
+    }
+}
+
+/// Forget the requests tracked in `RQ_TRACKER` and the `BLOCK_INFLIGHT` counts: the
+/// requests that complete while the block probes are detached are never released.
+pub fn reset(
+    mut tracker: HashMap<MapData, u64, RequestTrackerEntry>,
+    inflight: InflightMap,
+) -> Reset {
+    Box::new(move || {
+        clear(&mut tracker)?;
+        clear(&mut inflight.lock().unwrap())?;
+        Ok(())
+    })
+}
+
+fn clear<K: Pod, V: Pod>(map: &mut HashMap<MapData, K, V>) -> Result<(), MapError> {
+    let keys = map.keys().collect::<Result<Vec<K>, _>>()?;
+    for key in keys {
+        // Already gone if a program still running removed it
+        let _ = map.remove(&key);
+    }
+    Ok(())
+}
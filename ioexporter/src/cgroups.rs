@@ -0,0 +1,290 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use aya::maps::{MapData, PerCpuHashMap};
+use ioexporter_common::attribution::IoStats;
+use log::{debug, warn};
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+
+use crate::kubernetes::{self, Containers};
+use crate::stats::{self, IoStatsDescs};
+
+pub const DEFAULT_CGROUPFS_PATH: &str = "/sys/fs/cgroup";
+
+/// Resolves cgroup v2 ids, which are the inode numbers of the cgroup directories,
+/// to their path relative to the cgroupfs root, e.g. `/system.slice/docker.service`.
+pub struct CgroupResolver {
+    root: PathBuf,
+    cache: Mutex<Cache>,
+}
+
+struct Cache {
+    paths: HashMap<u64, String>,
+    // Ids not found by a walk, e.g. cgroups removed before they were scraped. Ids
+    // are never reused, so they are not looked up again until they are retained out
+    missing: HashSet<u64>,
+    // Whether the hierarchy may be walked again on the next unknown id
+    stale: bool,
+}
+
+impl CgroupResolver {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        CgroupResolver {
+            root: root.into(),
+            cache: Mutex::new(Cache {
+                paths: HashMap::new(),
+                missing: HashSet::new(),
+                stale: true,
+            }),
+        }
+    }
+
+    /// Allow the next lookup of a new unknown id to walk the hierarchy again, so that
+    /// cgroups created since are found. Meant to be called once per scrape.
+    pub fn refresh(&self) {
+        self.cache.lock().unwrap().stale = true;
+    }
+
+    pub fn resolve(&self, id: u64) -> Option<String> {
+        let mut cache = self.cache.lock().unwrap();
+        if let Some(path) = cache.paths.get(&id) {
+            return Some(path.clone());
+        }
+        if cache.missing.contains(&id) || !cache.stale {
+            return None;
+        }
+        cache.stale = false;
+        walk(&self.root, "", &mut cache.paths);
+        // Found ids are cached by the walk
+        let path = cache.paths.get(&id).cloned();
+        if path.is_none() {
+            cache.missing.insert(id);
+        }
+        path
+    }
+
+    /// Forget the cgroups that are not in `ids`. Paths of removed cgroups are kept
+    /// until then, so that their counters stay labelled.
+    pub fn retain(&self, ids: &HashSet<u64>) {
+        let mut cache = self.cache.lock().unwrap();
+        cache.paths.retain(|id, _| ids.contains(id));
+        cache.missing.retain(|id| ids.contains(id));
+    }
+}
+
+fn walk(dir: &Path, path: &str, paths: &mut HashMap<u64, String>) {
+    let metadata = match fs::metadata(dir) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            debug!("failed to stat {}: {}", dir.display(), e);
+            return;
+        }
+    };
+    paths.insert(
+        metadata.ino(),
+        if path.is_empty() { "/" } else { path }.to_string(),
+    );
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            debug!("failed to list {}: {}", dir.display(), e);
+            return;
+        }
+    };
+    for entry in entries.flatten() {
+        if entry.file_type().is_ok_and(|t| t.is_dir()) {
+            let child = format!("{}/{}", path, entry.file_name().to_string_lossy());
+            walk(&entry.path(), &child, paths);
+        }
+    }
+}
+
+/// Exposes the `CGROUP_BLOCK_STATS` counters with a `cgroup` path label and, when
+/// `kubernetes` is set, the `pod_uid`, `container_id` and `qos_class` parsed from the
+/// path. The `namespace`, `pod` and `container` labels are added when a CRI runtime
+/// is given to look containers up, and stay empty until the runtime answered. Meant to
+/// be wrapped in a `Filtered` only enabled with cgroup attribution.
+pub struct CgroupCollector {
+    map: PerCpuHashMap<MapData, u64, IoStats>,
+    resolver: Arc<CgroupResolver>,
+    kubernetes: bool,
+    cri: Option<Containers>,
+    descs: IoStatsDescs,
+}
+
+impl CgroupCollector {
+    pub fn new(
+        map: PerCpuHashMap<MapData, u64, IoStats>,
+        resolver: Arc<CgroupResolver>,
+        kubernetes: bool,
+        cri: Option<Containers>,
+    ) -> Result<Self, prometheus::Error> {
+        let mut labels = vec!["cgroup"];
+        if kubernetes {
+            labels.extend(["pod_uid", "container_id", "qos_class"]);
+            if cri.is_some() {
+                labels.extend(["namespace", "pod", "container"]);
+            }
+        }
+        let descs = IoStatsDescs::new(
+            "io_cgroup",
+            "per cgroup of the submitting task",
+            &labels,
+            true,
+        )?;
+        Ok(CgroupCollector {
+            map,
+            resolver,
+            kubernetes,
+            cri,
+            descs,
+        })
+    }
+
+    fn label_values(&self, path: String, containers: &mut HashSet<String>) -> Vec<String> {
+        if !self.kubernetes {
+            return vec![path];
+        }
+        let workload = kubernetes::parse(&path).unwrap_or_default();
+        let mut values = vec![
+            path,
+            workload.pod_uid,
+            workload.container_id.clone(),
+            workload.qos_class.to_string(),
+        ];
+        if let Some(cri) = &self.cri {
+            let info = match workload.container_id.is_empty() {
+                true => None,
+                false => {
+                    containers.insert(workload.container_id.clone());
+                    cri.get(&workload.container_id)
+                }
+            }
+            .unwrap_or_default();
+            values.extend([info.namespace, info.pod, info.container]);
+        }
+        values
+    }
+}
+
+impl Collector for CgroupCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.descs()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.resolver.refresh();
+        let mut ids = HashSet::new();
+        // A cgroup removed and created again with the same path gets a new id
+        let mut cgroups: BTreeMap<String, Vec<IoStats>> = BTreeMap::new();
+        for entry in self.map.iter() {
+            let (id, values) = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!("failed to read CGROUP_BLOCK_STATS: {}", e);
+                    continue;
+                }
+            };
+            ids.insert(id);
+            match self.resolver.resolve(id) {
+                Some(path) => cgroups.entry(path).or_default().extend(values.iter()),
+                // Removed before it was ever scraped
+                None => debug!("unknown cgroup id {}", id),
+            }
+        }
+        self.resolver.retain(&ids);
+        let mut containers = HashSet::new();
+        let cgroups: Vec<(Vec<String>, IoStats)> = cgroups
+            .into_iter()
+            .map(|(path, values)| {
+                (
+                    self.label_values(path, &mut containers),
+                    stats::total(&values),
+                )
+            })
+            .collect();
+        if let Some(cri) = &self.cri {
+            cri.retain(&containers);
+        }
+        self.descs.families(&cgroups)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(path: &Path) -> u64 {
+        fs::metadata(path).unwrap().ino()
+    }
+
+    #[test]
+    fn resolves_nested_cgroups() {
+        let root = tempfile::tempdir().unwrap();
+        let service = root.path().join("system.slice/docker.service");
+        fs::create_dir_all(&service).unwrap();
+        let resolver = CgroupResolver::new(root.path());
+
+        assert_eq!(resolver.resolve(id(root.path())).as_deref(), Some("/"));
+        assert_eq!(
+            resolver.resolve(id(&service)).as_deref(),
+            Some("/system.slice/docker.service")
+        );
+    }
+
+    #[test]
+    fn finds_new_cgroups_after_refresh() {
+        let root = tempfile::tempdir().unwrap();
+        let resolver = CgroupResolver::new(root.path());
+        resolver.resolve(id(root.path()));
+
+        let pod = root.path().join("kubepods.slice");
+        fs::create_dir(&pod).unwrap();
+        // Only one walk per scrape
+        assert_eq!(resolver.resolve(id(&pod)), None);
+        resolver.refresh();
+        assert_eq!(
+            resolver.resolve(id(&pod)).as_deref(),
+            Some("/kubepods.slice")
+        );
+    }
+
+    #[test]
+    fn caches_missing_ids() {
+        let root = tempfile::tempdir().unwrap();
+        let resolver = CgroupResolver::new(root.path());
+        let removed = id(root.path()) + 1_000_000;
+        assert_eq!(resolver.resolve(removed), None);
+
+        // A missing id does not walk the hierarchy again, a new one does
+        resolver.refresh();
+        assert_eq!(resolver.resolve(removed), None);
+        assert!(resolver.cache.lock().unwrap().stale);
+        let user = root.path().join("user.slice");
+        fs::create_dir(&user).unwrap();
+        assert_eq!(resolver.resolve(id(&user)).as_deref(), Some("/user.slice"));
+        assert!(!resolver.cache.lock().unwrap().stale);
+    }
+
+    #[test]
+    fn keeps_removed_cgroups_until_retained_out() {
+        let root = tempfile::tempdir().unwrap();
+        let job = root.path().join("job.scope");
+        fs::create_dir(&job).unwrap();
+        let job_id = id(&job);
+        let resolver = CgroupResolver::new(root.path());
+        assert_eq!(resolver.resolve(job_id).as_deref(), Some("/job.scope"));
+
+        fs::remove_dir(&job).unwrap();
+        resolver.refresh();
+        assert_eq!(resolver.resolve(job_id).as_deref(), Some("/job.scope"));
+
+        resolver.retain(&HashSet::from([id(root.path())]));
+        resolver.refresh();
+        assert_eq!(resolver.resolve(job_id), None);
+    }
+}
@@ -0,0 +1,149 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use clap::Parser;
+use log::LevelFilter;
+
+use crate::cgroups::DEFAULT_CGROUPFS_PATH;
+use crate::devices::DEFAULT_SYSFS_PATH;
+use crate::probes::ProbeGroup;
+
+/// Prometheus exporter for block, NVMe and page cache IO, backed by eBPF.
+#[derive(Debug, Parser)]
+#[clap(version)]
+pub struct Options {
+    /// TOML configuration file, reloaded on SIGHUP. Its settings override the flags below
+    #[clap(long = "config.file")]
+    pub config_file: Option<PathBuf>,
+    /// Address on which to expose metrics
+    #[clap(
+        long = "web.listen-address",
+        env = "IOEXPORTER_LISTEN_ADDRESS",
+        default_value = "0.0.0.0:9435"
+    )]
+    pub listen_address: SocketAddr,
+    /// Enable the pagecache collector (default: enabled)
+    #[clap(
+        long = "collector.pagecache",
+        overrides_with = "no_collector_pagecache"
+    )]
+    collector_pagecache: bool,
+    /// Disable the pagecache collector
+    #[clap(
+        long = "no-collector.pagecache",
+        overrides_with = "collector_pagecache"
+    )]
+    no_collector_pagecache: bool,
+    /// Enable the block collector (default: enabled)
+    #[clap(long = "collector.block", overrides_with = "no_collector_block")]
+    collector_block: bool,
+    /// Disable the block collector
+    #[clap(long = "no-collector.block", overrides_with = "collector_block")]
+    no_collector_block: bool,
+    /// Enable the nvme collector (default: enabled)
+    #[clap(long = "collector.nvme", overrides_with = "no_collector_nvme")]
+    collector_nvme: bool,
+    /// Disable the nvme collector
+    #[clap(long = "no-collector.nvme", overrides_with = "collector_nvme")]
+    no_collector_nvme: bool,
+    /// Break the NVMe latency down by namespace (nsid) and submission queue (qid)
+    #[clap(long = "collector.nvme.queue-labels")]
+    pub nvme_queue_labels: bool,
+    /// Report NVMe commands outstanding for longer than this many seconds as stuck
+    #[clap(long = "collector.nvme.stuck-threshold", default_value_t = 10.0)]
+    pub nvme_stuck_threshold: f64,
+    /// Also label block devices with their device-mapper name and LVM volume
+    #[clap(long = "collector.block.dm-names")]
+    pub dm_names: bool,
+    /// Attribute block and NVMe IO to the process that submitted it
+    #[clap(long = "collector.process")]
+    pub process_attribution: bool,
+    /// Only export the processes with the most IO bytes (then requests)
+    #[clap(long = "collector.process.top-n", default_value_t = 20)]
+    pub process_top_n: usize,
+    /// Attribute block IO to the cgroup of the task that submitted it
+    #[clap(long = "collector.cgroup")]
+    pub cgroup_attribution: bool,
+    /// Label cgroups with the Kubernetes pod and container parsed from their path
+    #[clap(long = "collector.cgroup.kubernetes")]
+    pub kubernetes_labels: bool,
+    /// CRI runtime endpoint used to add the namespace, pod and container names,
+    /// e.g. unix:///run/containerd/containerd.sock
+    #[clap(long = "collector.cgroup.cri-endpoint", requires = "kubernetes_labels")]
+    pub cri_endpoint: Option<String>,
+    /// Seconds to wait for the CRI runtime to answer a container lookup
+    #[clap(long = "collector.cgroup.cri-timeout", default_value_t = 2.0)]
+    pub cri_timeout: f64,
+    /// sysfs mount point, used to resolve device names
+    #[clap(long = "path.sysfs", default_value = DEFAULT_SYSFS_PATH)]
+    pub sysfs_path: PathBuf,
+    /// cgroup v2 mount point, used to resolve cgroup ids to paths
+    #[clap(long = "path.cgroupfs", default_value = DEFAULT_CGROUPFS_PATH)]
+    pub cgroupfs_path: PathBuf,
+    /// Only log messages with the given severity or above, unless RUST_LOG is set
+    #[clap(long = "log.level", default_value = "info")]
+    pub log_level: LevelFilter,
+    /// Merge the power of two histogram buckets so that only one bucket out of N is exported
+    #[clap(
+        long = "histogram.bucket-factor",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub bucket_factor: u32,
+}
+
+impl Options {
+    /// Whether the last of `--collector.X` and `--no-collector.X` enables the
+    /// collector (they override each other), enabled when neither is given.
+    pub fn collector_enabled(&self, group: ProbeGroup) -> bool {
+        match group {
+            ProbeGroup::PageCache => self.collector_pagecache || !self.no_collector_pagecache,
+            ProbeGroup::Block => self.collector_block || !self.no_collector_block,
+            ProbeGroup::Nvme => self.collector_nvme || !self.no_collector_nvme,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, clap::Error> {
+        Options::try_parse_from(["ioexporter"].iter().chain(args))
+    }
+
+    #[test]
+    fn enables_every_collector_by_default() {
+        let opts = parse(&[]).unwrap();
+        for group in ProbeGroup::ALL {
+            assert!(opts.collector_enabled(group), "{}", group.name());
+        }
+    }
+
+    #[test]
+    fn last_collector_flag_wins() {
+        let opts = parse(&["--collector.nvme", "--no-collector.nvme"]).unwrap();
+        assert!(!opts.collector_enabled(ProbeGroup::Nvme));
+        assert!(opts.collector_enabled(ProbeGroup::Block));
+
+        let opts = parse(&["--no-collector.nvme", "--collector.nvme"]).unwrap();
+        assert!(opts.collector_enabled(ProbeGroup::Nvme));
+
+        let opts = parse(&["--no-collector.pagecache", "--no-collector.block"]).unwrap();
+        assert!(!opts.collector_enabled(ProbeGroup::PageCache));
+        assert!(!opts.collector_enabled(ProbeGroup::Block));
+        assert!(opts.collector_enabled(ProbeGroup::Nvme));
+    }
+
+    #[test]
+    fn parses_the_bucket_factor() {
+        assert_eq!(parse(&[]).unwrap().bucket_factor, 1);
+        assert_eq!(
+            parse(&["--histogram.bucket-factor", "4"])
+                .unwrap()
+                .bucket_factor,
+            4
+        );
+        assert!(parse(&["--histogram.bucket-factor", "0"]).is_err());
+    }
+}
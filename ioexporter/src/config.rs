@@ -0,0 +1,265 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::cli::Options;
+use crate::probes::ProbeGroup;
+
+/// Content of the TOML configuration file, e.g.:
+///
+/// ```toml
+/// listen_address = "0.0.0.0:9435"
+///
+/// [collectors]
+/// nvme = false
+///
+/// [devices]
+/// allow = ["nvme.*", "sd[a-z]+"]
+/// deny = ["loop[0-9]+"]
+///
+/// [labels]
+/// datacenter = "par1"
+/// ```
+///
+/// Every setting is optional and falls back to the command-line value.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub listen_address: Option<SocketAddr>,
+    #[serde(default)]
+    pub collectors: CollectorsConfig,
+    #[serde(default)]
+    pub devices: DevicesConfig,
+    /// Static labels added to every exported metric, their names must be valid
+    /// Prometheus label names
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CollectorsConfig {
+    pub pagecache: Option<bool>,
+    pub block: Option<bool>,
+    pub nvme: Option<bool>,
+    /// Per process attribution of block and NVMe IO
+    pub process: Option<bool>,
+    /// Per cgroup attribution of block IO
+    pub cgroup: Option<bool>,
+}
+
+/// Regular expressions matched against the whole device name
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DevicesConfig {
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, anyhow::Error> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        toml::from_str(&content).with_context(|| format!("failed to parse {}", path.display()))
+    }
+}
+
+/// Effective runtime settings: the command line, overridden by the configuration file.
+pub struct Settings {
+    pub listen_address: SocketAddr,
+    collectors: BTreeMap<&'static str, bool>,
+    pub process_attribution: bool,
+    pub cgroup_attribution: bool,
+    pub devices: DeviceFilter,
+    pub labels: BTreeMap<String, String>,
+}
+
+impl Settings {
+    pub fn new(opts: &Options, config: &Config) -> Result<Self, anyhow::Error> {
+        let collectors = ProbeGroup::ALL
+            .iter()
+            .map(|group| {
+                let configured = match group {
+                    ProbeGroup::PageCache => config.collectors.pagecache,
+                    ProbeGroup::Block => config.collectors.block,
+                    ProbeGroup::Nvme => config.collectors.nvme,
+                };
+                (
+                    group.name(),
+                    configured.unwrap_or_else(|| opts.collector_enabled(*group)),
+                )
+            })
+            .collect();
+        for name in config.labels.keys() {
+            validate_label_name(name)?;
+        }
+        Ok(Settings {
+            listen_address: config.listen_address.unwrap_or(opts.listen_address),
+            collectors,
+            process_attribution: config
+                .collectors
+                .process
+                .unwrap_or(opts.process_attribution),
+            cgroup_attribution: config.collectors.cgroup.unwrap_or(opts.cgroup_attribution),
+            devices: DeviceFilter::new(&config.devices.allow, &config.devices.deny)?,
+            labels: config.labels.clone(),
+        })
+    }
+
+    pub fn collector_enabled(&self, group: ProbeGroup) -> bool {
+        self.collectors.get(group.name()).copied().unwrap_or(false)
+    }
+}
+
+/// Reject the names that would make the whole exposition invalid: Prometheus label
+/// names match `[a-zA-Z_][a-zA-Z0-9_]*` and those starting with `__` are reserved.
+fn validate_label_name(name: &str) -> Result<(), anyhow::Error> {
+    let mut chars = name.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        bail!("invalid label name {:?}", name);
+    }
+    if name.starts_with("__") {
+        bail!("label name {:?} is reserved", name);
+    }
+    Ok(())
+}
+
+/// Device allow/deny lists. A device is exported if it matches one of the allowed
+/// patterns (or there are none) and none of the denied ones.
+#[derive(Clone)]
+pub struct DeviceFilter {
+    allow: Option<Regex>,
+    deny: Option<Regex>,
+}
+
+impl DeviceFilter {
+    pub fn new(allow: &[String], deny: &[String]) -> Result<Self, regex::Error> {
+        Ok(DeviceFilter {
+            allow: compile(allow)?,
+            deny: compile(deny)?,
+        })
+    }
+
+    pub fn matches(&self, device: &str) -> bool {
+        self.allow.as_ref().is_none_or(|re| re.is_match(device))
+            && !self.deny.as_ref().is_some_and(|re| re.is_match(device))
+    }
+}
+
+fn compile(patterns: &[String]) -> Result<Option<Regex>, regex::Error> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let alternatives: Vec<String> = patterns.iter().map(|p| format!("(?:{})", p)).collect();
+    Regex::new(&format!("^(?:{})$", alternatives.join("|"))).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn filter(allow: &[&str], deny: &[&str]) -> DeviceFilter {
+        let strings =
+            |patterns: &[&str]| patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        DeviceFilter::new(&strings(allow), &strings(deny)).unwrap()
+    }
+
+    #[test]
+    fn accepts_valid_label_names() {
+        for name in ["datacenter", "_rack", "zone_2", "A"] {
+            assert!(validate_label_name(name).is_ok(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_label_names() {
+        for name in ["", "2zone", "data-center", "rack.id", "zoné", "__name"] {
+            assert!(validate_label_name(name).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn matches_every_device_without_patterns() {
+        let filter = filter(&[], &[]);
+        assert!(filter.matches("sda"));
+        assert!(filter.matches("nvme0n1"));
+    }
+
+    #[test]
+    fn anchors_the_patterns() {
+        let filter = filter(&["sd[a-z]+", "nvme0n1"], &[]);
+        assert!(filter.matches("sda"));
+        assert!(!filter.matches("sda1"));
+        assert!(!filter.matches("xsda"));
+        assert!(!filter.matches("nvme0n1p1"));
+    }
+
+    #[test]
+    fn deny_takes_priority_over_allow() {
+        let filter = filter(&["nvme.*", "loop[0-9]+"], &["loop[0-9]+", "nvme1n1"]);
+        assert!(filter.matches("nvme0n1"));
+        assert!(!filter.matches("nvme1n1"));
+        assert!(!filter.matches("loop0"));
+        assert!(!filter.matches("sda"));
+    }
+
+    #[test]
+    fn loads_the_configuration_file() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(
+            file,
+            r#"
+listen_address = "127.0.0.1:9500"
+
+[collectors]
+nvme = false
+process = true
+
+[devices]
+allow = ["nvme.*"]
+deny = ["nvme1n1"]
+
+[labels]
+datacenter = "par1"
+"#
+        )
+        .unwrap();
+        let config = Config::load(file.path()).unwrap();
+        assert_eq!(
+            config.listen_address,
+            Some("127.0.0.1:9500".parse().unwrap())
+        );
+        assert_eq!(config.collectors.nvme, Some(false));
+        assert_eq!(config.collectors.process, Some(true));
+        assert_eq!(config.collectors.block, None);
+        assert_eq!(config.devices.allow, ["nvme.*"]);
+        assert_eq!(config.devices.deny, ["nvme1n1"]);
+        assert_eq!(config.labels["datacenter"], "par1");
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(file, "[collectors]\nzfs = true\n").unwrap();
+        assert!(Config::load(file.path()).is_err());
+    }
+
+    #[test]
+    fn fails_to_load_a_missing_file() {
+        assert!(Config::load("/nonexistent/ioexporter.toml").is_err());
+    }
+}
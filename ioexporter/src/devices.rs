@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::{LabelPair, Metric, MetricFamily};
+
+pub const DEFAULT_SYSFS_PATH: &str = "/sys";
+
+/// Block device as seen in `<sysfs>/dev/block/<major>:<minor>`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BlockDevice {
+    /// Kernel name, e.g. `nvme0n1`, `sda2` or `dm-3`
+    pub name: String,
+    /// Device-mapper name, for dm devices
+    pub dm_name: Option<String>,
+    /// `<vg>/<lv>`, for dm devices managed by LVM
+    pub lvm_volume: Option<String>,
+}
+
+#[derive(Default)]
+struct Cache {
+    /// Entries of `<sysfs>/dev/block` when the cache was filled
+    entries: Vec<String>,
+    devices: HashMap<(u32, u32), Option<BlockDevice>>,
+}
+
+/// Resolves device numbers to names by reading sysfs, under a configurable root.
+/// Results are cached until a device is added or removed.
+pub struct DeviceResolver {
+    root: PathBuf,
+    cache: Mutex<Cache>,
+}
+
+impl DeviceResolver {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        DeviceResolver {
+            root: root.into(),
+            cache: Mutex::new(Cache::default()),
+        }
+    }
+
+    fn dev_block(&self) -> PathBuf {
+        self.root.join("dev/block")
+    }
+
+    /// Drop the cache if the set of block devices changed since it was filled.
+    pub fn refresh(&self) {
+        let mut entries: Vec<String> = match fs::read_dir(self.dev_block()) {
+            Ok(dir) => dir
+                .filter_map(|e| e.ok())
+                .map(|e| e.file_name().to_string_lossy().to_string())
+                .collect(),
+            Err(_) => vec![],
+        };
+        entries.sort();
+        let mut cache = self.cache.lock().unwrap();
+        if cache.entries != entries {
+            cache.devices.clear();
+            cache.entries = entries;
+        }
+    }
+
+    pub fn resolve(&self, major: u32, minor: u32) -> Option<BlockDevice> {
+        let mut cache = self.cache.lock().unwrap();
+        cache
+            .devices
+            .entry((major, minor))
+            .or_insert_with(|| {
+                read_device(&self.dev_block().join(format!("{}:{}", major, minor))).ok()
+            })
+            .clone()
+    }
+}
+
+fn read_device(path: &Path) -> io::Result<BlockDevice> {
+    let uevent = fs::read_to_string(path.join("uevent"))?;
+    let name = uevent
+        .lines()
+        .find_map(|line| line.strip_prefix("DEVNAME="))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no DEVNAME in uevent"))?
+        .to_string();
+    let read = |file: &str| {
+        fs::read_to_string(path.join(file))
+            .ok()
+            .map(|s| s.trim().to_string())
+    };
+    let dm_name = read("dm/name");
+    let lvm_volume = match (&dm_name, read("dm/uuid")) {
+        (Some(dm_name), Some(uuid)) if uuid.starts_with("LVM-") => lvm_volume(dm_name),
+        _ => None,
+    };
+    Ok(BlockDevice {
+        name,
+        dm_name,
+        lvm_volume,
+    })
+}
+
+/// LVM names its dm devices `<vg>-<lv>`, doubling the dashes within each part.
+fn lvm_volume(dm_name: &str) -> Option<String> {
+    let bytes = dm_name.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'-' {
+            if bytes.get(i + 1) == Some(&b'-') {
+                i += 2;
+                continue;
+            }
+            let vg = dm_name[..i].replace("--", "-");
+            let lv = dm_name[i + 1..].replace("--", "-");
+            return Some(format!("{}/{}", vg, lv));
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Adds a `device` label to the metrics of a collector that have `major` and `minor`
+/// labels, and optionally the device-mapper name and LVM volume.
+pub struct DeviceLabels<C> {
+    inner: C,
+    resolver: Arc<DeviceResolver>,
+    dm_names: bool,
+}
+
+impl<C: Collector> DeviceLabels<C> {
+    pub fn new(inner: C, resolver: Arc<DeviceResolver>, dm_names: bool) -> Self {
+        DeviceLabels {
+            inner,
+            resolver,
+            dm_names,
+        }
+    }
+
+    fn label(&self, metric: &mut Metric) {
+        let value = |name: &str| {
+            metric
+                .get_label()
+                .iter()
+                .find(|l| l.get_name() == name)
+                .and_then(|l| l.get_value().parse::<u32>().ok())
+        };
+        let (Some(major), Some(minor)) = (value("major"), value("minor")) else {
+            return;
+        };
+        let device = self.resolver.resolve(major, minor);
+        let name = match &device {
+            Some(device) => device.name.clone(),
+            None => format!("{}:{}", major, minor),
+        };
+        push_label(metric, "device", name);
+        if self.dm_names {
+            let device = device.unwrap_or_default();
+            push_label(metric, "dm_name", device.dm_name.unwrap_or_default());
+            push_label(metric, "lvm_volume", device.lvm_volume.unwrap_or_default());
+        }
+    }
+}
+
+impl<C: Collector> Collector for DeviceLabels<C> {
+    fn desc(&self) -> Vec<&Desc> {
+        self.inner.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.resolver.refresh();
+        let mut families = self.inner.collect();
+        for family in families.iter_mut() {
+            for metric in family.mut_metric().iter_mut() {
+                self.label(metric);
+            }
+        }
+        families
+    }
+}
+
+fn push_label(metric: &mut Metric, name: &str, value: String) {
+    let mut label = LabelPair::default();
+    label.set_name(name.to_string());
+    label.set_value(value);
+    metric.mut_label().push(label);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add_device(root: &Path, dev: &str, uevent: &str, dm: Option<(&str, &str)>) {
+        let path = root.join("dev/block").join(dev);
+        fs::create_dir_all(&path).unwrap();
+        fs::write(path.join("uevent"), uevent).unwrap();
+        if let Some((name, uuid)) = dm {
+            fs::create_dir_all(path.join("dm")).unwrap();
+            fs::write(path.join("dm/name"), format!("{}\n", name)).unwrap();
+            fs::write(path.join("dm/uuid"), format!("{}\n", uuid)).unwrap();
+        }
+    }
+
+    #[test]
+    fn resolves_disks_and_partitions() {
+        let root = tempfile::tempdir().unwrap();
+        add_device(
+            root.path(),
+            "259:0",
+            "MAJOR=259\nMINOR=0\nDEVNAME=nvme0n1\nDEVTYPE=disk\n",
+            None,
+        );
+        add_device(
+            root.path(),
+            "259:1",
+            "MAJOR=259\nMINOR=1\nDEVNAME=nvme0n1p1\nDEVTYPE=partition\nPARTN=1\n",
+            None,
+        );
+        let resolver = DeviceResolver::new(root.path());
+        resolver.refresh();
+
+        let disk = resolver.resolve(259, 0).unwrap();
+        assert_eq!(disk.name, "nvme0n1");
+        assert_eq!(disk.dm_name, None);
+        assert_eq!(resolver.resolve(259, 1).unwrap().name, "nvme0n1p1");
+        assert_eq!(resolver.resolve(8, 0), None);
+    }
+
+    #[test]
+    fn resolves_dm_names_and_lvm_volumes() {
+        let root = tempfile::tempdir().unwrap();
+        add_device(
+            root.path(),
+            "253:0",
+            "MAJOR=253\nMINOR=0\nDEVNAME=dm-0\nDEVTYPE=disk\n",
+            Some(("data--vg-var--log", "LVM-Jd8k2mN4pQ")),
+        );
+        add_device(
+            root.path(),
+            "253:1",
+            "MAJOR=253\nMINOR=1\nDEVNAME=dm-1\nDEVTYPE=disk\n",
+            Some(("luks-root", "CRYPT-LUKS2-0a1b2c-luks-root")),
+        );
+        let resolver = DeviceResolver::new(root.path());
+        resolver.refresh();
+
+        assert_eq!(
+            resolver.resolve(253, 0).unwrap(),
+            BlockDevice {
+                name: "dm-0".to_string(),
+                dm_name: Some("data--vg-var--log".to_string()),
+                lvm_volume: Some("data-vg/var-log".to_string()),
+            }
+        );
+        // Not an LVM volume, even though the name has a dash
+        assert_eq!(
+            resolver.resolve(253, 1).unwrap(),
+            BlockDevice {
+                name: "dm-1".to_string(),
+                dm_name: Some("luks-root".to_string()),
+                lvm_volume: None,
+            }
+        );
+    }
+
+    #[test]
+    fn parses_lvm_names() {
+        assert_eq!(lvm_volume("vg0-root"), Some("vg0/root".to_string()));
+        assert_eq!(lvm_volume("my--vg-my--lv"), Some("my-vg/my-lv".to_string()));
+        assert_eq!(lvm_volume("vg-lv-snap"), Some("vg/lv-snap".to_string()));
+        assert_eq!(lvm_volume("no--separator"), None);
+        assert_eq!(lvm_volume("root"), None);
+    }
+
+    #[test]
+    fn refreshes_after_hotplug() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("dev/block")).unwrap();
+        let resolver = DeviceResolver::new(root.path());
+        resolver.refresh();
+        assert_eq!(resolver.resolve(8, 16), None);
+
+        add_device(
+            root.path(),
+            "8:16",
+            "MAJOR=8\nMINOR=16\nDEVNAME=sdb\nDEVTYPE=disk\n",
+            None,
+        );
+        // Cached until the next refresh
+        assert_eq!(resolver.resolve(8, 16), None);
+        resolver.refresh();
+        assert_eq!(resolver.resolve(8, 16).unwrap().name, "sdb");
+
+        // The same device number reused by another disk
+        fs::remove_dir_all(root.path().join("dev/block/8:16")).unwrap();
+        resolver.refresh();
+        assert_eq!(resolver.resolve(8, 16), None);
+        add_device(
+            root.path(),
+            "8:16",
+            "MAJOR=8\nMINOR=16\nDEVNAME=sdc\nDEVTYPE=disk\n",
+            None,
+        );
+        resolver.refresh();
+        assert_eq!(resolver.resolve(8, 16).unwrap().name, "sdc");
+    }
+}
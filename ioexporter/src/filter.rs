@@ -0,0 +1,134 @@
+use std::sync::{Arc, RwLock};
+
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::{LabelPair, Metric, MetricFamily};
+
+use crate::config::Settings;
+use crate::probes::ProbeGroup;
+
+pub type SharedSettings = Arc<RwLock<Settings>>;
+
+/// Applies the runtime settings to the metrics of a collector on every scrape, so they
+/// can change on reload without recreating the collector (and losing its state):
+/// the collector is hidden when its probe group is disabled, devices rejected by the
+/// device filter are dropped and the configured static labels are added.
+pub struct Filtered<C> {
+    inner: C,
+    group: Option<ProbeGroup>,
+    enabled: Option<fn(&Settings) -> bool>,
+    settings: SharedSettings,
+}
+
+impl<C: Collector> Filtered<C> {
+    pub fn new(inner: C, group: Option<ProbeGroup>, settings: SharedSettings) -> Self {
+        Filtered {
+            inner,
+            group,
+            enabled: None,
+            settings,
+        }
+    }
+
+    /// Also hide the collector when `enabled` returns false, e.g. for the runtime
+    /// switches that are not probe groups.
+    pub fn only_if(mut self, enabled: fn(&Settings) -> bool) -> Self {
+        self.enabled = Some(enabled);
+        self
+    }
+}
+
+impl<C: Collector> Collector for Filtered<C> {
+    fn desc(&self) -> Vec<&Desc> {
+        self.inner.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        // Copied so that the lock is not held while collecting: a collector that reads
+        // the settings itself would deadlock behind a pending reload
+        let (devices, labels) = {
+            let settings = self.settings.read().unwrap();
+            if let Some(group) = self.group {
+                if !settings.collector_enabled(group) {
+                    return vec![];
+                }
+            }
+            if self.enabled.is_some_and(|enabled| !enabled(&settings)) {
+                return vec![];
+            }
+            (settings.devices.clone(), settings.labels.clone())
+        };
+
+        let mut families = self.inner.collect();
+        for family in families.iter_mut() {
+            let metrics = family
+                .take_metric()
+                .into_iter()
+                .filter(|m| device(m).is_none_or(|d| devices.matches(&d)))
+                .map(|mut m| {
+                    for (name, value) in labels.iter() {
+                        if !m.get_label().iter().any(|l| l.get_name() == name) {
+                            let mut label = LabelPair::default();
+                            label.set_name(name.clone());
+                            label.set_value(value.clone());
+                            m.mut_label().push(label);
+                        }
+                    }
+                    m
+                })
+                .collect();
+            family.set_metric(metrics);
+        }
+        families.retain(|f| !f.get_metric().is_empty());
+        families
+    }
+}
+
+/// Name of the device a metric is about: its `device` or `disk` label, or `major:minor`.
+fn device(metric: &Metric) -> Option<String> {
+    let label = |name: &str| {
+        metric
+            .get_label()
+            .iter()
+            .find(|l| l.get_name() == name)
+            .map(|l| l.get_value().trim_end_matches('\0').to_string())
+    };
+    label("device").or_else(|| label("disk")).or_else(|| {
+        let major = label("major")?;
+        let minor = label("minor")?;
+        Some(format!("{}:{}", major, minor))
+    })
+}
+
+/// Removes the given labels from every metric of a collector. The remaining labels
+/// must still identify each metric, e.g. because the eBPF side zeroed the removed ones.
+pub struct WithoutLabels<C> {
+    inner: C,
+    labels: &'static [&'static str],
+}
+
+impl<C: Collector> WithoutLabels<C> {
+    pub fn new(inner: C, labels: &'static [&'static str]) -> Self {
+        WithoutLabels { inner, labels }
+    }
+}
+
+impl<C: Collector> Collector for WithoutLabels<C> {
+    fn desc(&self) -> Vec<&Desc> {
+        self.inner.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut families = self.inner.collect();
+        for family in families.iter_mut() {
+            for metric in family.mut_metric().iter_mut() {
+                let labels = metric
+                    .take_label()
+                    .into_iter()
+                    .filter(|l| !self.labels.contains(&l.get_name()))
+                    .collect();
+                metric.set_label(labels);
+            }
+        }
+        families
+    }
+}
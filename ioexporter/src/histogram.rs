@@ -0,0 +1,119 @@
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::{MetricFamily, MetricType};
+
+/// Wraps a histogram collector to export a coarser bucket layout.
+/// The eBPF histograms use one bucket per power of two; with a factor of N only one
+/// bucket out of N is kept. Buckets are cumulative, so dropping some of them leaves
+/// the remaining ones, the sum and the count untouched.
+pub struct Rebucketed<C> {
+    inner: C,
+    factor: usize,
+}
+
+impl<C: Collector> Rebucketed<C> {
+    pub fn new(inner: C, factor: u32) -> Self {
+        Rebucketed {
+            inner,
+            factor: factor.max(1) as usize,
+        }
+    }
+}
+
+impl<C: Collector> Collector for Rebucketed<C> {
+    fn desc(&self) -> Vec<&Desc> {
+        self.inner.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut families = self.inner.collect();
+        if self.factor == 1 {
+            return families;
+        }
+        for family in families
+            .iter_mut()
+            .filter(|f| f.get_field_type() == MetricType::HISTOGRAM)
+        {
+            for metric in family.mut_metric().iter_mut() {
+                let buckets = metric.mut_histogram().mut_bucket();
+                let last = buckets.len().saturating_sub(1);
+                let kept = buckets
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, b)| {
+                        *i == last
+                            || b.get_upper_bound().is_infinite()
+                            || (i + 1) % self.factor == 0
+                    })
+                    .map(|(_, b)| b.clone())
+                    .collect();
+                *buckets = kept;
+            }
+        }
+        families
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus::proto::{Bucket, Histogram, Metric};
+
+    use super::*;
+
+    struct Fixed(Vec<MetricFamily>);
+
+    impl Collector for Fixed {
+        fn desc(&self) -> Vec<&Desc> {
+            vec![]
+        }
+
+        fn collect(&self) -> Vec<MetricFamily> {
+            self.0.clone()
+        }
+    }
+
+    /// Power of two histogram from 1 to 128, with one observation per bucket.
+    fn histogram() -> Fixed {
+        let mut histogram = Histogram::default();
+        for (i, upper_bound) in [1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0, 128.0]
+            .into_iter()
+            .enumerate()
+        {
+            let mut bucket = Bucket::default();
+            bucket.set_upper_bound(upper_bound);
+            bucket.set_cumulative_count(i as u64 + 1);
+            histogram.mut_bucket().push(bucket);
+        }
+        histogram.set_sample_count(8);
+        histogram.set_sample_sum(255.0);
+        let mut metric = Metric::default();
+        metric.set_histogram(histogram);
+        let mut family = MetricFamily::default();
+        family.set_name("io_disk_latency".to_string());
+        family.set_field_type(MetricType::HISTOGRAM);
+        family.mut_metric().push(metric);
+        Fixed(vec![family])
+    }
+
+    fn upper_bounds(factor: u32) -> Vec<f64> {
+        let families = Rebucketed::new(histogram(), factor).collect();
+        let histogram = families[0].get_metric()[0].get_histogram();
+        assert_eq!(histogram.get_sample_count(), 8);
+        assert_eq!(histogram.get_sample_sum(), 255.0);
+        histogram
+            .get_bucket()
+            .iter()
+            .map(|b| b.get_upper_bound())
+            .collect()
+    }
+
+    #[test]
+    fn keeps_every_bucket_with_a_factor_of_one() {
+        assert_eq!(upper_bounds(1).len(), 8);
+    }
+
+    #[test]
+    fn keeps_one_bucket_out_of_n_and_the_last_one() {
+        assert_eq!(upper_bounds(2), [2.0, 8.0, 32.0, 128.0]);
+        assert_eq!(upper_bounds(3), [4.0, 32.0, 128.0]);
+    }
+}
@@ -0,0 +1,40 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub const KALLSYMS_PATH: &str = "/proc/kallsyms";
+
+/// Functions of the running kernel, as listed in `/proc/kallsyms`.
+pub struct KernelSymbols {
+    functions: HashSet<String>,
+}
+
+impl KernelSymbols {
+    pub fn load() -> io::Result<Self> {
+        Self::from_path(KALLSYMS_PATH)
+    }
+
+    pub fn from_path<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::parse(&fs::read_to_string(path)?))
+    }
+
+    /// Parse lines such as `ffffffff8123a4b0 T mark_page_accessed`, keeping only text symbols.
+    pub fn parse(content: &str) -> Self {
+        let functions = content
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let _address = fields.next()?;
+                let kind = fields.next()?;
+                let name = fields.next()?;
+                matches!(kind, "t" | "T").then(|| name.to_string())
+            })
+            .collect();
+        KernelSymbols { functions }
+    }
+
+    pub fn contains(&self, function: &str) -> bool {
+        self.functions.contains(function)
+    }
+}
@@ -0,0 +1,488 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail};
+use hyper::body::{Bytes, HttpBody};
+use hyper::{Body, Request};
+use log::{debug, warn};
+use prost::Message;
+use tokio::net::UnixStream;
+use tokio::sync::mpsc;
+
+/// Kubernetes workload a cgroup belongs to, as encoded in its path by the kubelet.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Workload {
+    pub pod_uid: String,
+    /// Empty for the pod cgroup itself
+    pub container_id: String,
+    pub qos_class: &'static str,
+}
+
+/// Parse the path of a pod or container cgroup, with either the systemd driver:
+/// `/kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod<uid>.slice/cri-containerd-<id>.scope`
+/// or the cgroupfs one: `/kubepods/burstable/pod<uid>/<id>`. Guaranteed pods have
+/// no QoS level, e.g. `/kubepods/pod<uid>/<id>`.
+pub fn parse(path: &str) -> Option<Workload> {
+    let mut components = path.split('/').filter(|c| !c.is_empty());
+    let root = components.next()?;
+    if root != "kubepods" && root != "kubepods.slice" {
+        return None;
+    }
+    let mut component = components.next()?;
+    let qos_class = match component {
+        "burstable" | "kubepods-burstable.slice" => "burstable",
+        "besteffort" | "kubepods-besteffort.slice" => "besteffort",
+        _ => "guaranteed",
+    };
+    if qos_class != "guaranteed" {
+        component = components.next()?;
+    }
+    let pod_uid = match component.strip_suffix(".slice") {
+        // The systemd driver escapes the dashes of the uid
+        Some(slice) => slice.rsplit_once("-pod")?.1.replace('_', "-"),
+        None => component.strip_prefix("pod")?.to_string(),
+    };
+    let container_id = match components.next() {
+        Some(container) => match container.strip_suffix(".scope") {
+            // cri-containerd-<id>, crio-<id>, docker-<id>; crio-conmon-<id> is the
+            // container monitor, not the container
+            Some(scope) if !scope.contains("conmon") => scope.rsplit('-').next()?.to_string(),
+            Some(_) => String::new(),
+            None => container.to_string(),
+        },
+        None => String::new(),
+    };
+    Some(Workload {
+        pod_uid,
+        container_id,
+        qos_class,
+    })
+}
+
+/// Names of a container, as reported by the container runtime.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ContainerInfo {
+    pub namespace: String,
+    pub pod: String,
+    pub container: String,
+}
+
+// The subset of the CRI messages that is needed, see
+// https://github.com/kubernetes/cri-api/blob/master/pkg/apis/runtime/v1/api.proto
+const CONTAINER_STATUS_PATH: &str = "/runtime.v1.RuntimeService/ContainerStatus";
+
+#[derive(Clone, PartialEq, Message)]
+struct ContainerStatusRequest {
+    #[prost(string, tag = "1")]
+    container_id: String,
+    #[prost(bool, tag = "2")]
+    verbose: bool,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ContainerStatusResponse {
+    #[prost(message, optional, tag = "1")]
+    status: Option<ContainerStatus>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ContainerStatus {
+    #[prost(map = "string, string", tag = "12")]
+    labels: HashMap<String, String>,
+}
+
+enum Lookup {
+    Pending,
+    /// None when the lookup failed
+    Done(Option<ContainerInfo>),
+}
+
+/// Containers looked up by a `CriClient`. Meant to be read while scraping: unknown
+/// containers are queued for the client and have no names until it answered.
+#[derive(Clone)]
+pub struct Containers {
+    cache: Arc<Mutex<HashMap<String, Lookup>>>,
+    lookups: mpsc::UnboundedSender<String>,
+}
+
+impl Containers {
+    pub fn get(&self, id: &str) -> Option<ContainerInfo> {
+        let mut cache = self.cache.lock().unwrap();
+        match cache.get(id) {
+            Some(Lookup::Done(info)) => info.clone(),
+            Some(Lookup::Pending) => None,
+            None => {
+                cache.insert(id.to_string(), Lookup::Pending);
+                // Only fails once the client stopped, the container stays pending
+                let _ = self.lookups.send(id.to_string());
+                None
+            }
+        }
+    }
+
+    /// Forget the containers that are not in `ids`.
+    pub fn retain(&self, ids: &HashSet<String>) {
+        self.cache.lock().unwrap().retain(|id, _| ids.contains(id));
+    }
+}
+
+/// Looks containers up with the `ContainerStatus` call of a CRI runtime endpoint,
+/// e.g. `unix:///run/containerd/containerd.sock`, off the scrape path. Answers,
+/// including failures, are cached per container id.
+pub struct CriClient {
+    socket: PathBuf,
+    timeout: Duration,
+    containers: Containers,
+    lookups: mpsc::UnboundedReceiver<String>,
+}
+
+impl CriClient {
+    pub fn new(endpoint: &str, timeout: Duration) -> Result<Self, anyhow::Error> {
+        let socket = match endpoint.strip_prefix("unix://") {
+            Some(path) => path,
+            None if endpoint.starts_with('/') => endpoint,
+            None => bail!(
+                "unsupported CRI endpoint {}, expected unix://<path>",
+                endpoint
+            ),
+        };
+        let (sender, lookups) = mpsc::unbounded_channel();
+        Ok(CriClient {
+            socket: PathBuf::from(socket),
+            timeout,
+            containers: Containers {
+                cache: Arc::new(Mutex::new(HashMap::new())),
+                lookups: sender,
+            },
+            lookups,
+        })
+    }
+
+    pub fn containers(&self) -> Containers {
+        self.containers.clone()
+    }
+
+    /// Answer the queued lookups one at a time, forever.
+    pub async fn run(mut self) {
+        while let Some(id) = self.lookups.recv().await {
+            let info = self.lookup(&id).await;
+            let mut cache = self.containers.cache.lock().unwrap();
+            // Not cached anymore if retained out in the meantime
+            if let Some(entry) = cache.get_mut(&id) {
+                *entry = Lookup::Done(info);
+            }
+        }
+    }
+
+    async fn lookup(&self, id: &str) -> Option<ContainerInfo> {
+        debug!("looking up container {}", id);
+        match tokio::time::timeout(self.timeout, self.container_status(id)).await {
+            Ok(Ok(info)) => Some(info),
+            Ok(Err(e)) => {
+                warn!("failed to look up container {}: {:#}", id, e);
+                None
+            }
+            Err(_) => {
+                warn!(
+                    "failed to look up container {}: no answer from {} after {:?}",
+                    id,
+                    self.socket.display(),
+                    self.timeout
+                );
+                None
+            }
+        }
+    }
+
+    async fn container_status(&self, id: &str) -> Result<ContainerInfo, anyhow::Error> {
+        let stream = UnixStream::connect(&self.socket).await?;
+        let (mut sender, connection) = hyper::client::conn::Builder::new()
+            .http2_only(true)
+            .handshake(stream)
+            .await?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                debug!("CRI connection failed: {}", e);
+            }
+        });
+
+        let message = ContainerStatusRequest {
+            container_id: id.to_string(),
+            verbose: false,
+        };
+        let request = Request::post(format!("http://localhost{}", CONTAINER_STATUS_PATH))
+            .header("content-type", "application/grpc")
+            .header("te", "trailers")
+            .body(Body::from(grpc_frame(&message.encode_to_vec())))?;
+        let response = sender.send_request(request).await?;
+        if !response.status().is_success() {
+            bail!("CRI runtime answered with HTTP {}", response.status());
+        }
+        // Errors come without a body, with the status in the headers
+        let headers = response.headers().clone();
+        let mut body = response.into_body();
+        let mut data = Vec::new();
+        while let Some(chunk) = body.data().await {
+            data.extend_from_slice(&chunk?);
+        }
+        let trailers = body.trailers().await?.unwrap_or_default();
+        let header = |name: &str| {
+            trailers
+                .get(name)
+                .or_else(|| headers.get(name))
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default()
+                .to_string()
+        };
+        let status = header("grpc-status");
+        if status != "0" {
+            bail!(
+                "CRI runtime answered with gRPC status {}: {}",
+                status,
+                header("grpc-message")
+            );
+        }
+
+        let response = ContainerStatusResponse::decode(grpc_message(&data)?)?;
+        let labels = response.status.unwrap_or_default().labels;
+        let label = |name: &str| labels.get(name).cloned().unwrap_or_default();
+        Ok(ContainerInfo {
+            namespace: label("io.kubernetes.pod.namespace"),
+            pod: label("io.kubernetes.pod.name"),
+            container: label("io.kubernetes.container.name"),
+        })
+    }
+}
+
+/// Prefix a message with the gRPC framing: not compressed, then its length.
+fn grpc_frame(message: &[u8]) -> Bytes {
+    let mut frame = Vec::with_capacity(5 + message.len());
+    frame.push(0);
+    frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    frame.extend_from_slice(message);
+    Bytes::from(frame)
+}
+
+fn grpc_message(frame: &[u8]) -> Result<&[u8], anyhow::Error> {
+    let (header, message) = frame
+        .split_at_checked(5)
+        .ok_or_else(|| anyhow!("truncated gRPC message"))?;
+    if header[0] != 0 {
+        bail!("compressed gRPC messages are not supported");
+    }
+    let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    message
+        .get(..len)
+        .ok_or_else(|| anyhow!("truncated gRPC message"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::time::Instant;
+
+    use hyper::service::service_fn;
+    use hyper::{HeaderMap, Response};
+    use tokio::net::UnixListener;
+
+    use super::*;
+
+    fn workload(pod_uid: &str, container_id: &str, qos_class: &'static str) -> Option<Workload> {
+        Some(Workload {
+            pod_uid: pod_uid.to_string(),
+            container_id: container_id.to_string(),
+            qos_class,
+        })
+    }
+
+    #[test]
+    fn parses_systemd_paths() {
+        let cases = [
+            (
+                "/kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod1c3b8f2e_9d1e_4a4b.slice/cri-containerd-ab12cd.scope",
+                workload("1c3b8f2e-9d1e-4a4b", "ab12cd", "burstable"),
+            ),
+            (
+                "/kubepods.slice/kubepods-besteffort.slice/kubepods-besteffort-pod7f00_01.slice/crio-ef34.scope",
+                workload("7f00-01", "ef34", "besteffort"),
+            ),
+            (
+                "/kubepods.slice/kubepods-pod42_aa.slice/docker-9a8b.scope",
+                workload("42-aa", "9a8b", "guaranteed"),
+            ),
+            (
+                "/kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod7f00_01.slice",
+                workload("7f00-01", "", "burstable"),
+            ),
+            (
+                "/kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod7f00_01.slice/crio-conmon-ef34.scope",
+                workload("7f00-01", "", "burstable"),
+            ),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(parse(path), expected, "{}", path);
+        }
+    }
+
+    #[test]
+    fn parses_cgroupfs_paths() {
+        let cases = [
+            (
+                "/kubepods/burstable/pod1c3b8f2e-9d1e-4a4b/ab12cd",
+                workload("1c3b8f2e-9d1e-4a4b", "ab12cd", "burstable"),
+            ),
+            (
+                "/kubepods/besteffort/pod7f00-01/ef34",
+                workload("7f00-01", "ef34", "besteffort"),
+            ),
+            (
+                "/kubepods/pod42-aa/9a8b",
+                workload("42-aa", "9a8b", "guaranteed"),
+            ),
+            ("/kubepods/pod42-aa", workload("42-aa", "", "guaranteed")),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(parse(path), expected, "{}", path);
+        }
+    }
+
+    #[test]
+    fn ignores_other_cgroups() {
+        for path in [
+            "",
+            "/",
+            "/system.slice/containerd.service",
+            "/user.slice/user-1000.slice",
+            "/kubepods.slice",
+            "/kubepods.slice/kubepods-burstable.slice",
+            "/kubepods/besteffort",
+            "/kubepods/besteffort/notapod",
+        ] {
+            assert_eq!(parse(path), None, "{}", path);
+        }
+    }
+
+    /// Answers `ContainerStatus` for the container `c0ffee` only, like a CRI runtime
+    /// would: the message in the body and the status in the trailers, or only a
+    /// status when the container is unknown.
+    async fn container_status(request: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+        assert_eq!(request.uri().path(), CONTAINER_STATUS_PATH);
+        let frame = hyper::body::to_bytes(request.into_body()).await?;
+        let request = ContainerStatusRequest::decode(grpc_message(&frame).unwrap()).unwrap();
+        let response = Response::builder().header("content-type", "application/grpc");
+        if request.container_id != "c0ffee" {
+            return Ok(response
+                .header("grpc-status", "5")
+                .header("grpc-message", "container not found")
+                .body(Body::empty())
+                .unwrap());
+        }
+        let labels = HashMap::from([
+            ("io.kubernetes.pod.namespace", "kube-system"),
+            ("io.kubernetes.pod.name", "coredns-5d78c9869d-x2x9k"),
+            ("io.kubernetes.container.name", "coredns"),
+        ])
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        let message = ContainerStatusResponse {
+            status: Some(ContainerStatus { labels }),
+        };
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            sender
+                .send_data(grpc_frame(&message.encode_to_vec()))
+                .await
+                .unwrap();
+            let mut trailers = HeaderMap::new();
+            trailers.insert("grpc-status", "0".parse().unwrap());
+            sender.send_trailers(trailers).await.unwrap();
+        });
+        Ok(response.body(body).unwrap())
+    }
+
+    fn fake_cri(socket: &Path) {
+        let listener = UnixListener::bind(socket).unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(
+                    hyper::server::conn::Http::new()
+                        .http2_only(true)
+                        .serve_connection(stream, service_fn(container_status)),
+                );
+            }
+        });
+    }
+
+    fn coredns() -> ContainerInfo {
+        ContainerInfo {
+            namespace: "kube-system".to_string(),
+            pod: "coredns-5d78c9869d-x2x9k".to_string(),
+            container: "coredns".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn looks_containers_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("cri.sock");
+        fake_cri(&socket);
+        let endpoint = format!("unix://{}", socket.display());
+        let client = CriClient::new(&endpoint, Duration::from_secs(5)).unwrap();
+
+        assert_eq!(client.container_status("c0ffee").await.unwrap(), coredns());
+        let e = client.container_status("deadbeef").await.unwrap_err();
+        assert!(e.to_string().contains("container not found"), "{}", e);
+    }
+
+    #[tokio::test]
+    async fn looks_containers_up_in_the_background() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("cri.sock");
+        fake_cri(&socket);
+        let client = CriClient::new(socket.to_str().unwrap(), Duration::from_secs(5)).unwrap();
+        let containers = client.containers();
+
+        // Queued, not resolved while scraping
+        assert_eq!(containers.get("c0ffee"), None);
+        assert_eq!(containers.get("deadbeef"), None);
+        tokio::spawn(client.run());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while containers.get("c0ffee").is_none() {
+            assert!(Instant::now() < deadline, "container never resolved");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(containers.get("c0ffee"), Some(coredns()));
+        assert_eq!(containers.get("deadbeef"), None);
+
+        containers.retain(&HashSet::new());
+        assert!(containers.cache.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_a_hung_runtime() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("cri.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        // Accepts connections and never answers
+        tokio::spawn(async move {
+            let mut streams = vec![];
+            loop {
+                streams.push(listener.accept().await.unwrap());
+            }
+        });
+        let client = CriClient::new(socket.to_str().unwrap(), Duration::from_millis(100)).unwrap();
+
+        let start = Instant::now();
+        assert_eq!(client.lookup("c0ffee").await, None);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn rejects_other_endpoints() {
+        assert!(CriClient::new("tcp://127.0.0.1:10010", Duration::from_secs(1)).is_err());
+    }
+}
@@ -1,76 +1,94 @@
-use aya::maps::{PerCpuArray, PerCpuHashMap};
-use aya::programs::{BtfTracePoint, KProbe, TracePoint};
-use aya::{include_bytes_aligned, Bpf, Btf, Pod};
+mod block;
+mod cgroups;
+mod cli;
+mod config;
+mod devices;
+mod filter;
+mod histogram;
+mod kallsyms;
+mod kubernetes;
+mod metrics;
+mod nvme;
+mod pagecache;
+mod probes;
+mod process;
+mod server;
+mod stats;
+
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use aya::maps::{HashMap, PerCpuArray, PerCpuHashMap};
+use aya::{include_bytes_aligned, Bpf};
 use aya_log::BpfLogger;
-// use libc::name_t;
-use ebpf_histogram::{Histogram, Key, KeyWrapper};
+use block::InflightCollector;
+use cgroups::{CgroupCollector, CgroupResolver};
+use clap::Parser;
+use cli::Options;
+use config::{Config, Settings};
+use devices::{DeviceLabels, DeviceResolver};
+use ebpf_histogram::{Histogram, KeyWrapper};
+use filter::{Filtered, SharedSettings, WithoutLabels};
+use histogram::Rebucketed;
+use ioexporter_common::attribution::{
+    IoStats, ProcessKey, CGROUP_BLOCK_STATS_MAP, PROCESS_BLOCK_STATS_MAP, PROCESS_NVME_STATS_MAP,
+};
+use ioexporter_common::block::{
+    DiskHistogramKey, DiskLatencyHistogramKey, RequestTrackerEntry, BLOCK_HISTOGRAM_MAP,
+    BLOCK_INFLIGHT_MAP, BLOCK_QUEUE_DEPTH_HISTOGRAM_MAP, BLOCK_QUEUE_HISTOGRAM_MAP,
+    BLOCK_SERVICE_HISTOGRAM_MAP, BLOCK_SIZE_HISTOGRAM_MAP, RQ_TRACKER_MAP,
+};
+use ioexporter_common::config::{
+    CGROUP_ATTRIBUTION_CONFIG_IDX, NVME_QUEUE_LABELS_CONFIG_IDX, PROCESS_ATTRIBUTION_CONFIG_IDX,
+};
+use ioexporter_common::nvme::{
+    NvmeAdminHistogramKey, NvmeCompletionKey, NvmeHistogramKey, NvmeTrackerEntry, NvmeTrackerKey,
+    DISK_NAME_LEN, NVME_ADMIN_HISTOGRAM_MAP, NVME_BLOCK_SIZES_MAP, NVME_COMPLETIONS_MAP,
+    NVME_HISTOGRAM_MAP, NVME_METRICS_MAP, STATE_TRACKER_MAP,
+};
+use ioexporter_common::pagecache::PAGE_CACHE_METRICS_MAP;
+use kallsyms::KernelSymbols;
+use kubernetes::CriClient;
 use log::{debug, info, warn};
-use phf::phf_map;
-use prometheus::{Opts, Registry, TextEncoder};
+use nvme::{BlockSizes, NvmeCompletionsCollector, NvmeCountersCollector, StuckCommandsScanner};
+use pagecache::PageCacheCollector;
+use probes::{ProbeGroup, Probes};
+use process::ProcessCollector;
+use prometheus::{Opts, Registry};
 use tokio::signal;
+use tokio::signal::unix::{signal as unix_signal, SignalKind};
 
-static OP_CODE: phf::Map<u8, &'static str> = phf_map! {
-    0x00u8 => "nvme_cmd_flush",
-    0x01u8 => "nvme_cmd_write",
-    0x02u8 => "nvme_cmd_read",
-    0x04u8 => "nvme_cmd_write_uncor",
-    0x05u8 => "nvme_cmd_compare",
-    0x08u8 => "nvme_cmd_write_zeroes",
-    0x09u8 => "nvme_cmd_dsm",
-    0x0du8 => "nvme_cmd_resv_register",
-    0x0eu8 => "nvme_cmd_resv_report",
-    0x11u8 => "nvme_cmd_resv_acquire",
-    0x15u8 => "nvme_cmd_resv_release",
-};
-
-#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
-// #[derive(Key)]
-#[repr(C)]
-pub struct DiskLatencyHistogramKey {
-    pub major: i32,
-    pub minor: i32,
-}
-
-unsafe impl Send for DiskLatencyHistogramKey {}
-unsafe impl Sync for DiskLatencyHistogramKey {}
-unsafe impl Pod for DiskLatencyHistogramKey {}
-impl Key for DiskLatencyHistogramKey {
-    fn get_label_keys() -> Vec<String> {
-        vec!["major".to_string(), "minor".to_string()]
-    }
-
-    fn get_label_values(&self) -> Vec<String> {
-        vec![self.major.to_string(), self.minor.to_string()]
-    }
-}
-
-#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
-#[repr(C)]
-pub struct NvneHistogramKey {
-    // In practice, 31 first bytes are the disk and the last is opcode
-    // This is done because of adding a mere u8 would require adding 32 bytes to keep alignement
-    pub opaque: [u8; 32],
-}
-
-unsafe impl Send for NvneHistogramKey {}
-unsafe impl Sync for NvneHistogramKey {}
-unsafe impl Pod for NvneHistogramKey {}
-impl Key for NvneHistogramKey {
-    fn get_label_keys() -> Vec<String> {
-        vec!["disk".to_string(), "operation".to_string()]
-    }
-
-    fn get_label_values(&self) -> Vec<String> {
-        vec![
-            String::from_utf8_lossy(&self.opaque[0..31]).to_string(),
-            OP_CODE[&self.opaque[31]].to_string(),
-        ]
-    }
-}
+/// Block histogram maps, with the name and help of the histogram they are exported as
+const BLOCK_HISTOGRAMS: [(&str, &str, &str); 4] = [
+    (
+        BLOCK_HISTOGRAM_MAP,
+        "io_disk_latency",
+        "Histogram of IO latency, from insertion in the scheduler (or dispatch when it is bypassed) to completion",
+    ),
+    (
+        BLOCK_QUEUE_HISTOGRAM_MAP,
+        "io_disk_queue_latency",
+        "Histogram of the time IO spent queued in the scheduler, from insertion to dispatch",
+    ),
+    (
+        BLOCK_SERVICE_HISTOGRAM_MAP,
+        "io_disk_service_latency",
+        "Histogram of the device service time of IO, from dispatch to completion",
+    ),
+    (
+        BLOCK_SIZE_HISTOGRAM_MAP,
+        "io_disk_request_bytes",
+        "Histogram of IO request sizes in bytes",
+    ),
+];
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
-    env_logger::init();
+    let opts = Options::parse();
+    env_logger::Builder::from_env(
+        env_logger::Env::default().default_filter_or(opts.log_level.as_str()),
+    )
+    .init();
 
     // Bump the memlock rlimit. This is needed for older kernels that don't use the
     // new memcg based accounting, see https://lwn.net/Articles/837122/
@@ -99,79 +117,345 @@ async fn main() -> Result<(), anyhow::Error> {
         // This can happen if you remove all log statements from your eBPF program.
         warn!("failed to initialize eBPF logger: {}", e);
     }
-    let program: &mut KProbe = bpf.program_mut("mark_page_accessed").unwrap().try_into()?;
-    program.load()?;
-    program.attach("mark_page_accessed", 0)?;
-    let program: &mut KProbe = bpf
-        .program_mut("add_to_page_cache_lru")
-        .unwrap()
-        .try_into()?;
-    program.load()?;
-    program.attach("add_to_page_cache_lru", 0)?;
-    let program: &mut KProbe = bpf.program_mut("mark_buffer_dirty").unwrap().try_into()?;
-    program.load()?;
-    program.attach("mark_buffer_dirty", 0)?;
-    let program: &mut BtfTracePoint = bpf.program_mut("block_rq_insert").unwrap().try_into()?;
-    let btf = Btf::from_sys_fs()?;
-    program.load("block_rq_insert", &btf)?;
-    program.attach()?;
-    let program: &mut BtfTracePoint = bpf.program_mut("block_rq_complete").unwrap().try_into()?;
-    let btf = Btf::from_sys_fs()?;
-    program.load("block_rq_complete", &btf)?;
-    program.attach()?;
-    // sudo ls /sys/kernel/debug/tracing/events/ to find category
-    let program: &mut TracePoint = bpf.program_mut("nvme_setup_cmd").unwrap().try_into()?;
-    program.load()?;
-    program.attach("nvme", "nvme_setup_cmd")?;
-    let program: &mut TracePoint = bpf.program_mut("nvme_complete_rq").unwrap().try_into()?;
-    program.load()?;
-    program.attach("nvme", "nvme_complete_rq")?;
-
+    let symbols = match KernelSymbols::load() {
+        Ok(symbols) => Some(symbols),
+        Err(e) => {
+            warn!("failed to read {}: {}", kallsyms::KALLSYMS_PATH, e);
+            None
+        }
+    };
     let page_cache_metrics: PerCpuArray<_, u64> = PerCpuArray::try_from(
-        bpf.take_map("PAGE_CACHE_METRICS")
-            .expect("failed to map IP_MAP"),
+        bpf.take_map(PAGE_CACHE_METRICS_MAP)
+            .expect("failed to map PAGE_CACHE_METRICS"),
     )?;
 
-    let io_latency_map: PerCpuHashMap<_, KeyWrapper<DiskLatencyHistogramKey>, u64> =
+    let mut block_histograms = vec![];
+    for (map, name, help) in BLOCK_HISTOGRAMS {
+        let map: PerCpuHashMap<_, KeyWrapper<DiskLatencyHistogramKey>, u64> =
+            PerCpuHashMap::try_from(
+                bpf.take_map(map)
+                    .unwrap_or_else(|| panic!("failed to map {}", map)),
+            )?;
+        let histogram: Histogram<DiskLatencyHistogramKey> =
+            Histogram::new_from_map(map, Opts::new(name, help));
+        block_histograms.push(histogram);
+    }
+    let queue_depth_map: PerCpuHashMap<_, KeyWrapper<DiskHistogramKey>, u64> =
         PerCpuHashMap::try_from(
-            bpf.take_map("BLOCK_HISTOGRAM")
-                .expect("failed to map BLOCK_HISTOGRAM"),
+            bpf.take_map(BLOCK_QUEUE_DEPTH_HISTOGRAM_MAP)
+                .expect("failed to map BLOCK_QUEUE_DEPTH_HISTOGRAM"),
         )?;
-    let nvme_latency_map: PerCpuHashMap<_, KeyWrapper<NvneHistogramKey>, u64> =
+    let queue_depth_histogram: Histogram<DiskHistogramKey> = Histogram::new_from_map(
+        queue_depth_map,
+        Opts::new(
+            "io_disk_queue_depth",
+            "Histogram of the number of IO in flight on the device, sampled when an IO is issued",
+        ),
+    );
+    let inflight_map: HashMap<_, DiskHistogramKey, i64> = HashMap::try_from(
+        bpf.take_map(BLOCK_INFLIGHT_MAP)
+            .expect("failed to map BLOCK_INFLIGHT"),
+    )?;
+    let inflight_map = Arc::new(Mutex::new(inflight_map));
+    let rq_tracker_map: HashMap<_, u64, RequestTrackerEntry> = HashMap::try_from(
+        bpf.take_map(RQ_TRACKER_MAP)
+            .expect("failed to map RQ_TRACKER"),
+    )?;
+    let nvme_latency_map: PerCpuHashMap<_, KeyWrapper<NvmeHistogramKey>, u64> =
         PerCpuHashMap::try_from(
-            bpf.take_map("NVME_HISTOGRAM")
+            bpf.take_map(NVME_HISTOGRAM_MAP)
                 .expect("failed to map NVME_HISTOGRAM"),
         )?;
 
-    let io_latency_histogram: Histogram<DiskLatencyHistogramKey> = Histogram::new_from_map(
-        io_latency_map,
-        Opts::new("io_disk_latency", "Histogram of IO latency"),
-    );
-    let nvme_latency_histogram: Histogram<NvneHistogramKey> = Histogram::new_from_map(
+    let nvme_latency_histogram: Histogram<NvmeHistogramKey> = Histogram::new_from_map(
         nvme_latency_map,
         Opts::new("nvme_latency", "Histogram of IO latency"),
     );
+    let nvme_admin_latency_map: PerCpuHashMap<_, KeyWrapper<NvmeAdminHistogramKey>, u64> =
+        PerCpuHashMap::try_from(
+            bpf.take_map(NVME_ADMIN_HISTOGRAM_MAP)
+                .expect("failed to map NVME_ADMIN_HISTOGRAM"),
+        )?;
+    let nvme_admin_latency_histogram: Histogram<NvmeAdminHistogramKey> = Histogram::new_from_map(
+        nvme_admin_latency_map,
+        Opts::new(
+            "nvme_admin_latency",
+            "Histogram of NVMe admin command latency",
+        ),
+    );
+
+    let nvme_metrics: PerCpuArray<_, u64> = PerCpuArray::try_from(
+        bpf.take_map(NVME_METRICS_MAP)
+            .expect("failed to map NVME_METRICS"),
+    )?;
+    let nvme_completions_map: PerCpuHashMap<_, NvmeCompletionKey, u64> = PerCpuHashMap::try_from(
+        bpf.take_map(NVME_COMPLETIONS_MAP)
+            .expect("failed to map NVME_COMPLETIONS"),
+    )?;
+    let nvme_tracker_map: HashMap<_, NvmeTrackerKey, NvmeTrackerEntry> = HashMap::try_from(
+        bpf.take_map(STATE_TRACKER_MAP)
+            .expect("failed to map STATE_TRACKER"),
+    )?;
+    let nvme_block_sizes_map: HashMap<_, [u8; DISK_NAME_LEN], u32> = HashMap::try_from(
+        bpf.take_map(NVME_BLOCK_SIZES_MAP)
+            .expect("failed to map NVME_BLOCK_SIZES"),
+    )?;
+    let process_block_map: PerCpuHashMap<_, ProcessKey, IoStats> = PerCpuHashMap::try_from(
+        bpf.take_map(PROCESS_BLOCK_STATS_MAP)
+            .expect("failed to map PROCESS_BLOCK_STATS"),
+    )?;
+    let process_nvme_map: PerCpuHashMap<_, ProcessKey, IoStats> = PerCpuHashMap::try_from(
+        bpf.take_map(PROCESS_NVME_STATS_MAP)
+            .expect("failed to map PROCESS_NVME_STATS"),
+    )?;
+
+    let cgroup_block_map: PerCpuHashMap<_, u64, IoStats> = PerCpuHashMap::try_from(
+        bpf.take_map(CGROUP_BLOCK_STATS_MAP)
+            .expect("failed to map CGROUP_BLOCK_STATS"),
+    )?;
+
+    let config = match &opts.config_file {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let settings: SharedSettings = Arc::new(RwLock::new(Settings::new(&opts, &config)?));
+
+    let mut probes = Probes::new(bpf, symbols)?;
+    probes.on_reset(
+        ProbeGroup::Block,
+        block::reset(rq_tracker_map, inflight_map.clone()),
+    );
+    // Not part of the reloadable settings: the label set of a histogram is fixed
+    probes.set_config(NVME_QUEUE_LABELS_CONFIG_IDX, opts.nvme_queue_labels as u32)?;
+    apply_settings(&mut probes, &settings.read().unwrap());
+
+    let cri_client = match &opts.cri_endpoint {
+        Some(endpoint) => Some(CriClient::new(
+            endpoint,
+            Duration::try_from_secs_f64(opts.cri_timeout)?,
+        )?),
+        None => None,
+    };
 
     let r = Registry::new();
-    r.register(Box::new(io_latency_histogram)).unwrap();
-    r.register(Box::new(nvme_latency_histogram)).unwrap();
-    println!("Starting exporter");
-    println!("Waiting for Ctrl-C...");
-    signal::ctrl_c().await?;
-    println!("mark_page_accessed: {:?}", page_cache_metrics.get(&0, 0)?);
-    println!(
-        "add_to_page_cache_lru: {:?}",
-        page_cache_metrics.get(&1, 0)?
+    r.register(Box::new(Filtered::new(probes.up(), None, settings.clone())))
+        .unwrap();
+    let device_resolver = Arc::new(DeviceResolver::new(&opts.sysfs_path));
+    for histogram in block_histograms {
+        r.register(Box::new(Filtered::new(
+            DeviceLabels::new(
+                Rebucketed::new(histogram, opts.bucket_factor),
+                device_resolver.clone(),
+                opts.dm_names,
+            ),
+            Some(ProbeGroup::Block),
+            settings.clone(),
+        )))
+        .unwrap();
+    }
+    r.register(Box::new(Filtered::new(
+        DeviceLabels::new(
+            Rebucketed::new(queue_depth_histogram, opts.bucket_factor),
+            device_resolver.clone(),
+            opts.dm_names,
+        ),
+        Some(ProbeGroup::Block),
+        settings.clone(),
+    )))
+    .unwrap();
+    r.register(Box::new(Filtered::new(
+        DeviceLabels::new(
+            InflightCollector::new(inflight_map)?,
+            device_resolver.clone(),
+            opts.dm_names,
+        ),
+        Some(ProbeGroup::Block),
+        settings.clone(),
+    )))
+    .unwrap();
+    let nvme_latency = Rebucketed::new(nvme_latency_histogram, opts.bucket_factor);
+    let nvme_latency = WithoutLabels::new(
+        nvme_latency,
+        if opts.nvme_queue_labels {
+            &[]
+        } else {
+            &["nsid", "qid"]
+        },
     );
-    // println!("histo: {:?}", histogram.export_to_le_histogram());
-    println!("mark_buffer_dirty: {:?}", page_cache_metrics.get(&2, 0)?);
+    r.register(Box::new(Filtered::new(
+        nvme_latency,
+        Some(ProbeGroup::Nvme),
+        settings.clone(),
+    )))
+    .unwrap();
+    r.register(Box::new(Filtered::new(
+        Rebucketed::new(nvme_admin_latency_histogram, opts.bucket_factor),
+        Some(ProbeGroup::Nvme),
+        settings.clone(),
+    )))
+    .unwrap();
+    r.register(Box::new(Filtered::new(
+        NvmeCountersCollector::new(nvme_metrics)?,
+        Some(ProbeGroup::Nvme),
+        settings.clone(),
+    )))
+    .unwrap();
+    let stuck_commands = StuckCommandsScanner::new(
+        nvme_tracker_map,
+        Duration::try_from_secs_f64(opts.nvme_stuck_threshold)?,
+        &opts.sysfs_path,
+        settings.clone(),
+    )?;
+    r.register(Box::new(Filtered::new(
+        stuck_commands.metrics(),
+        Some(ProbeGroup::Nvme),
+        settings.clone(),
+    )))
+    .unwrap();
+    r.register(Box::new(Filtered::new(
+        NvmeCompletionsCollector::new(nvme_completions_map)?,
+        Some(ProbeGroup::Nvme),
+        settings.clone(),
+    )))
+    .unwrap();
+    r.register(Box::new(
+        Filtered::new(
+            ProcessCollector::new(process_block_map, "io_process", true, opts.process_top_n)?,
+            Some(ProbeGroup::Block),
+            settings.clone(),
+        )
+        .only_if(|settings| settings.process_attribution),
+    ))
+    .unwrap();
+    r.register(Box::new(
+        Filtered::new(
+            ProcessCollector::new(process_nvme_map, "nvme_process", true, opts.process_top_n)?,
+            Some(ProbeGroup::Nvme),
+            settings.clone(),
+        )
+        .only_if(|settings| settings.process_attribution),
+    ))
+    .unwrap();
+    r.register(Box::new(
+        Filtered::new(
+            CgroupCollector::new(
+                cgroup_block_map,
+                Arc::new(CgroupResolver::new(&opts.cgroupfs_path)),
+                opts.kubernetes_labels,
+                cri_client.as_ref().map(CriClient::containers),
+            )?,
+            Some(ProbeGroup::Block),
+            settings.clone(),
+        )
+        .only_if(|settings| settings.cgroup_attribution),
+    ))
+    .unwrap();
+    r.register(Box::new(Filtered::new(
+        PageCacheCollector::new(page_cache_metrics, settings.clone())?,
+        None,
+        settings.clone(),
+    )))
+    .unwrap();
+
+    let block_sizes = BlockSizes::new(nvme_block_sizes_map, &opts.sysfs_path);
+
+    if let Some(cri_client) = cri_client {
+        tokio::spawn(cri_client.run());
+    }
+
+    info!("Starting exporter");
+    let listen_address = settings.read().unwrap().listen_address;
+    tokio::select! {
+        res = server::serve(listen_address, r, shutdown_signal()) => res?,
+        Err(e) = reload_on_sighup(&opts, &mut probes, &settings) => return Err(e),
+        _ = stuck_commands.run() => {}
+        _ = block_sizes.run() => {}
+    }
     info!("Exiting...");
 
-    let mut buffer = String::new();
-    let encoder = TextEncoder::new();
-    let metric_families = r.gather();
-    encoder.encode_utf8(&metric_families, &mut buffer).unwrap();
-    println!("{}", buffer);
+    Ok(())
+}
+
+/// Attach the probe groups enabled in `settings` that are not attached yet
+/// (including those that failed previously), detach the disabled ones and
+/// forward the runtime switches to the eBPF programs.
+fn apply_settings(probes: &mut Probes, settings: &Settings) {
+    if let Err(e) = probes.set_config(
+        PROCESS_ATTRIBUTION_CONFIG_IDX,
+        settings.process_attribution as u32,
+    ) {
+        warn!("failed to configure process attribution: {:#}", e);
+    }
+    if let Err(e) = probes.set_config(
+        CGROUP_ATTRIBUTION_CONFIG_IDX,
+        settings.cgroup_attribution as u32,
+    ) {
+        warn!("failed to configure cgroup attribution: {:#}", e);
+    }
+    for group in ProbeGroup::ALL {
+        match (settings.collector_enabled(group), probes.is_attached(group)) {
+            (true, false) => {
+                probes.attach(group);
+            }
+            (false, true) => {
+                probes.detach(group);
+                info!("{} collector disabled", group.name());
+            }
+            _ => {}
+        }
+    }
+}
 
+/// Re-read the configuration file on every SIGHUP and apply it. The eBPF maps are kept
+/// as is, so histograms and counters survive a reload.
+async fn reload_on_sighup(
+    opts: &Options,
+    probes: &mut Probes,
+    settings: &SharedSettings,
+) -> Result<(), anyhow::Error> {
+    let mut sighup = unix_signal(SignalKind::hangup())?;
+    while sighup.recv().await.is_some() {
+        let Some(path) = &opts.config_file else {
+            info!("Received SIGHUP without a configuration file, ignoring");
+            continue;
+        };
+        info!("Received SIGHUP, reloading {}", path.display());
+        let new = match Config::load(path).and_then(|config| Settings::new(opts, &config)) {
+            Ok(new) => new,
+            Err(e) => {
+                warn!(
+                    "failed to reload configuration, keeping the current one: {:#}",
+                    e
+                );
+                continue;
+            }
+        };
+        if new.listen_address != settings.read().unwrap().listen_address {
+            warn!(
+                "listen address changed to {}, a restart is needed to apply it",
+                new.listen_address
+            );
+        }
+        // Attaching can take a while, only lock the settings (and block the
+        // scrapes) to swap them.
+        apply_settings(probes, &new);
+        *settings.write().unwrap() = new;
+    }
     Ok(())
 }
+
+/// Resolve on the first SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    let mut sigterm = match unix_signal(SignalKind::terminate()) {
+        Ok(sigterm) => sigterm,
+        Err(e) => {
+            warn!("failed to install SIGTERM handler: {}", e);
+            let _ = signal::ctrl_c().await;
+            return;
+        }
+    };
+    tokio::select! {
+        _ = signal::ctrl_c() => info!("Received SIGINT"),
+        _ = sigterm.recv() => info!("Received SIGTERM"),
+    }
+}
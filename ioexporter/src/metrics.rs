@@ -0,0 +1,46 @@
+use prometheus::core::Desc;
+use prometheus::proto::{Counter, Gauge, LabelPair, Metric, MetricFamily, MetricType};
+
+pub fn new_desc(name: &str, help: &str, labels: &[&str]) -> Result<Desc, prometheus::Error> {
+    Desc::new(
+        name.to_string(),
+        help.to_string(),
+        labels.iter().map(|l| l.to_string()).collect(),
+        Default::default(),
+    )
+}
+
+/// Build the family described by `desc` with one counter or gauge per set of label
+/// values, given in the order of the desc variable labels.
+pub fn family<I>(desc: &Desc, metric_type: MetricType, values: I) -> MetricFamily
+where
+    I: IntoIterator<Item = (Vec<String>, f64)>,
+{
+    let mut family = MetricFamily::default();
+    family.set_name(desc.fq_name.clone());
+    family.set_help(desc.help.clone());
+    family.set_field_type(metric_type);
+    for (label_values, value) in values {
+        let mut metric = Metric::default();
+        for (name, value) in desc.variable_labels.iter().zip(label_values) {
+            let mut label = LabelPair::default();
+            label.set_name(name.clone());
+            label.set_value(value);
+            metric.mut_label().push(label);
+        }
+        match metric_type {
+            MetricType::COUNTER => {
+                let mut counter = Counter::default();
+                counter.set_value(value);
+                metric.set_counter(counter);
+            }
+            _ => {
+                let mut gauge = Gauge::default();
+                gauge.set_value(value);
+                metric.set_gauge(gauge);
+            }
+        }
+        family.mut_metric().push(metric);
+    }
+    family
+}
@@ -0,0 +1,564 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use aya::maps::{HashMap, MapData, PerCpuArray, PerCpuHashMap};
+use ioexporter_common::nvme::{
+    self, NvmeCompletionKey, NvmeTrackerEntry, NvmeTrackerKey, DISK_NAME_LEN,
+    TRACKER_MISSES_COUNTER_IDX, TRACKER_OVERWRITES_COUNTER_IDX,
+};
+use log::{debug, warn};
+use phf::phf_map;
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::{MetricFamily, MetricType};
+use prometheus::{IntGauge, IntGaugeVec, Opts};
+
+use crate::filter::SharedSettings;
+use crate::metrics::{family, new_desc};
+use crate::probes::ProbeGroup;
+
+const COUNTERS: [(u32, &str, &str); 2] = [
+    (
+        TRACKER_OVERWRITES_COUNTER_IDX,
+        "nvme_tracker_overwrites_total",
+        "Number of NVMe commands set up while a command with the same controller, queue and command id was still tracked",
+    ),
+    (
+        TRACKER_MISSES_COUNTER_IDX,
+        "nvme_tracker_misses_total",
+        "Number of NVMe commands completed without being tracked, because they were set up before the probes were attached or evicted from the tracker",
+    ),
+];
+
+/// Exposes the `NVME_METRICS` counters, summed over all CPUs.
+pub struct NvmeCountersCollector {
+    map: PerCpuArray<MapData, u64>,
+    descs: Vec<Desc>,
+}
+
+impl NvmeCountersCollector {
+    pub fn new(map: PerCpuArray<MapData, u64>) -> Result<Self, prometheus::Error> {
+        let descs = COUNTERS
+            .iter()
+            .map(|(_, name, help)| new_desc(name, help, &[]))
+            .collect::<Result<_, _>>()?;
+        Ok(NvmeCountersCollector { map, descs })
+    }
+}
+
+impl Collector for NvmeCountersCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        COUNTERS
+            .iter()
+            .zip(self.descs.iter())
+            .filter_map(|((idx, _, _), desc)| match self.map.get(idx, 0) {
+                Ok(values) => {
+                    let value: u64 = values.iter().sum();
+                    Some(family(desc, MetricType::COUNTER, [(vec![], value as f64)]))
+                }
+                Err(e) => {
+                    warn!("failed to read NVMe counter {}: {}", idx, e);
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+// Status codes, keyed by status code type << 8 | status code
+// https://elixir.bootlin.com/linux/latest/source/include/linux/nvme.h
+static STATUS: phf::Map<u16, &'static str> = phf_map! {
+    0x000u16 => "generic/success",
+    0x001u16 => "generic/invalid_opcode",
+    0x002u16 => "generic/invalid_field",
+    0x003u16 => "generic/cmdid_conflict",
+    0x004u16 => "generic/data_xfer_error",
+    0x005u16 => "generic/power_loss",
+    0x006u16 => "generic/internal",
+    0x007u16 => "generic/abort_req",
+    0x008u16 => "generic/abort_queue",
+    0x009u16 => "generic/fused_fail",
+    0x00au16 => "generic/fused_missing",
+    0x00bu16 => "generic/invalid_ns",
+    0x00cu16 => "generic/cmd_seq_error",
+    0x00du16 => "generic/sgl_invalid_last",
+    0x00eu16 => "generic/sgl_invalid_count",
+    0x00fu16 => "generic/sgl_invalid_data",
+    0x010u16 => "generic/sgl_invalid_metadata",
+    0x011u16 => "generic/sgl_invalid_type",
+    0x012u16 => "generic/cmb_invalid_use",
+    0x013u16 => "generic/prp_invalid_offset",
+    0x014u16 => "generic/atomic_wu_exceeded",
+    0x015u16 => "generic/operation_denied",
+    0x016u16 => "generic/sgl_invalid_offset",
+    0x018u16 => "generic/host_id_inconsist",
+    0x019u16 => "generic/kato_expired",
+    0x01au16 => "generic/kato_invalid",
+    0x01bu16 => "generic/abort_preempt",
+    0x01cu16 => "generic/sanitize_failed",
+    0x01du16 => "generic/sanitize_in_progress",
+    0x01eu16 => "generic/sgl_invalid_granularity",
+    0x01fu16 => "generic/cmd_not_sup_cmb_queue",
+    0x020u16 => "generic/ns_write_protected",
+    0x021u16 => "generic/cmd_interrupted",
+    0x022u16 => "generic/transient_tr_err",
+    0x024u16 => "generic/admin_command_media_not_ready",
+    0x080u16 => "generic/lba_range",
+    0x081u16 => "generic/cap_exceeded",
+    0x082u16 => "generic/ns_not_ready",
+    0x083u16 => "generic/reservation_conflict",
+    0x084u16 => "generic/format_in_progress",
+    0x100u16 => "command_specific/cq_invalid",
+    0x101u16 => "command_specific/qid_invalid",
+    0x102u16 => "command_specific/queue_size",
+    0x103u16 => "command_specific/abort_limit",
+    0x105u16 => "command_specific/async_limit",
+    0x106u16 => "command_specific/firmware_slot",
+    0x107u16 => "command_specific/firmware_image",
+    0x108u16 => "command_specific/invalid_vector",
+    0x109u16 => "command_specific/invalid_log_page",
+    0x10au16 => "command_specific/invalid_format",
+    0x10bu16 => "command_specific/fw_needs_conv_reset",
+    0x10cu16 => "command_specific/invalid_queue",
+    0x10du16 => "command_specific/feature_not_saveable",
+    0x10eu16 => "command_specific/feature_not_changeable",
+    0x10fu16 => "command_specific/feature_not_per_ns",
+    0x110u16 => "command_specific/fw_needs_subsys_reset",
+    0x111u16 => "command_specific/fw_needs_reset",
+    0x112u16 => "command_specific/fw_needs_max_time",
+    0x113u16 => "command_specific/fw_activate_prohibited",
+    0x114u16 => "command_specific/overlapping_range",
+    0x115u16 => "command_specific/ns_insufficient_cap",
+    0x116u16 => "command_specific/ns_id_unavailable",
+    0x118u16 => "command_specific/ns_already_attached",
+    0x119u16 => "command_specific/ns_is_private",
+    0x11au16 => "command_specific/ns_not_attached",
+    0x11bu16 => "command_specific/thin_prov_not_supp",
+    0x11cu16 => "command_specific/ctrl_list_invalid",
+    0x11du16 => "command_specific/self_test_in_progress",
+    0x11eu16 => "command_specific/bp_write_prohibited",
+    0x11fu16 => "command_specific/ctrl_id_invalid",
+    0x120u16 => "command_specific/sec_ctrl_state_invalid",
+    0x121u16 => "command_specific/ctrl_res_num_invalid",
+    0x122u16 => "command_specific/res_id_invalid",
+    0x123u16 => "command_specific/pmr_san_prohibited",
+    0x124u16 => "command_specific/ana_group_id_invalid",
+    0x125u16 => "command_specific/ana_attach_failed",
+    0x180u16 => "command_specific/bad_attributes",
+    0x181u16 => "command_specific/invalid_pi",
+    0x182u16 => "command_specific/read_only",
+    0x1b8u16 => "command_specific/zone_boundary_error",
+    0x1b9u16 => "command_specific/zone_full",
+    0x1bau16 => "command_specific/zone_read_only",
+    0x1bbu16 => "command_specific/zone_offline",
+    0x1bcu16 => "command_specific/zone_invalid_write",
+    0x1bdu16 => "command_specific/zone_too_many_active",
+    0x1beu16 => "command_specific/zone_too_many_open",
+    0x1bfu16 => "command_specific/zone_invalid_transition",
+    0x280u16 => "media/write_fault",
+    0x281u16 => "media/unrecovered_read_error",
+    0x282u16 => "media/guard_check",
+    0x283u16 => "media/apptag_check",
+    0x284u16 => "media/reftag_check",
+    0x285u16 => "media/compare_failed",
+    0x286u16 => "media/access_denied",
+    0x287u16 => "media/unwritten_block",
+    0x300u16 => "path/internal_path_error",
+    0x301u16 => "path/ana_persistent_loss",
+    0x302u16 => "path/ana_inaccessible",
+    0x303u16 => "path/ana_transition",
+    0x360u16 => "path/ctrl_path_error",
+    0x370u16 => "path/host_path_error",
+    0x371u16 => "path/host_aborted_cmd",
+};
+
+const STATUS_CODE_TYPES: [&str; 8] = [
+    "generic",
+    "command_specific",
+    "media",
+    "path",
+    "sct_4",
+    "sct_5",
+    "sct_6",
+    "vendor",
+];
+
+/// Name of a completion status as `<status code type>/<status code>`, with the
+/// status code in hexadecimal when it is unknown (e.g. vendor specific). The Do Not
+/// Retry and More bits, when set, are ignored.
+pub fn status_name(status: u16) -> String {
+    let status = status & 0x7ff;
+    match STATUS.get(&status) {
+        Some(name) => name.to_string(),
+        None => format!(
+            "{}/{:#04x}",
+            STATUS_CODE_TYPES[(status >> 8 & 0x7) as usize],
+            status & 0xff
+        ),
+    }
+}
+
+/// Exposes the `NVME_COMPLETIONS` counts as `nvme_completions_total`.
+pub struct NvmeCompletionsCollector {
+    map: PerCpuHashMap<MapData, NvmeCompletionKey, u64>,
+    desc: Desc,
+}
+
+impl NvmeCompletionsCollector {
+    pub fn new(
+        map: PerCpuHashMap<MapData, NvmeCompletionKey, u64>,
+    ) -> Result<Self, prometheus::Error> {
+        let desc = new_desc(
+            "nvme_completions_total",
+            "Number of completed NVMe commands, per completion status",
+            &["disk", "operation", "status"],
+        )?;
+        Ok(NvmeCompletionsCollector { map, desc })
+    }
+}
+
+impl Collector for NvmeCompletionsCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let values = self.map.iter().filter_map(|entry| match entry {
+            Ok((key, values)) => {
+                let labels = vec![
+                    nvme::disk_name(&key.disk),
+                    nvme::operation(&key.disk, key.opcode),
+                    status_name(key.status),
+                ];
+                Some((labels, values.iter().sum::<u64>() as f64))
+            }
+            Err(e) => {
+                warn!("failed to read NVME_COMPLETIONS: {}", e);
+                None
+            }
+        });
+        vec![family(&self.desc, MetricType::COUNTER, values)]
+    }
+}
+
+/// Periodically looks for commands of `STATE_TRACKER` set up for longer than a
+/// threshold, which would otherwise go unnoticed until the kernel nvme timeout
+/// fires or the LRU evicts them.
+pub struct StuckCommandsScanner {
+    map: HashMap<MapData, NvmeTrackerKey, NvmeTrackerEntry>,
+    threshold: Duration,
+    lost_after: Duration,
+    settings: SharedSettings,
+    outstanding: IntGauge,
+    stuck: IntGaugeVec,
+    // Commands already logged, so that each is only reported once
+    reported: HashSet<(NvmeTrackerKey, u64)>,
+}
+
+impl StuckCommandsScanner {
+    pub fn new(
+        map: HashMap<MapData, NvmeTrackerKey, NvmeTrackerEntry>,
+        threshold: Duration,
+        sysfs: &Path,
+        settings: SharedSettings,
+    ) -> Result<Self, prometheus::Error> {
+        let outstanding = IntGauge::new(
+            "nvme_outstanding_commands",
+            "Number of NVMe commands set up and not completed yet",
+        )?;
+        let stuck = IntGaugeVec::new(
+            Opts::new(
+                "nvme_stuck_commands",
+                "Number of NVMe commands outstanding for longer than the stuck threshold",
+            ),
+            &["disk", "operation"],
+        )?;
+        Ok(StuckCommandsScanner {
+            map,
+            threshold,
+            lost_after: lost_after(sysfs).max(threshold),
+            settings,
+            outstanding,
+            stuck,
+            reported: HashSet::new(),
+        })
+    }
+
+    /// Gauges updated by the scans, to register.
+    pub fn metrics(&self) -> StuckCommandsMetrics {
+        StuckCommandsMetrics {
+            outstanding: self.outstanding.clone(),
+            stuck: self.stuck.clone(),
+        }
+    }
+
+    /// Scan the tracker every second, forever.
+    pub async fn run(mut self) {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            // Detached programs leave their last commands behind
+            if self
+                .settings
+                .read()
+                .unwrap()
+                .collector_enabled(ProbeGroup::Nvme)
+            {
+                self.scan();
+            }
+        }
+    }
+
+    fn scan(&mut self) {
+        // Same clock as bpf_ktime_get_ns
+        let now = match monotonic_ns() {
+            Some(now) => now,
+            None => return,
+        };
+        let threshold = self.threshold.as_nanos() as u64;
+        let lost_after = self.lost_after.as_nanos() as u64;
+        let mut outstanding = 0;
+        let mut stuck: BTreeMap<(String, String), i64> = BTreeMap::new();
+        let mut reported = HashSet::new();
+        let mut lost = vec![];
+        for entry in self.map.iter() {
+            let (key, entry) = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!("failed to read STATE_TRACKER: {}", e);
+                    continue;
+                }
+            };
+            let age = now.saturating_sub(entry.from);
+            let disk = nvme::disk_name(&entry.disk);
+            let operation = nvme::operation(&entry.disk, entry.opcode);
+            if age >= lost_after {
+                warn!(
+                    "nvme command outstanding for {:.1}s, its completion was missed, forgetting it: disk={} operation={} ctrl_id={} qid={} cid={}",
+                    age as f64 / 1e9,
+                    disk,
+                    operation,
+                    key.ctrl_id,
+                    key.qid,
+                    key.cid
+                );
+                lost.push((key, entry.from));
+                continue;
+            }
+            outstanding += 1;
+            if age < threshold {
+                continue;
+            }
+            if !self.reported.contains(&(key, entry.from)) {
+                warn!(
+                    "nvme command outstanding for {:.1}s: disk={} operation={} ctrl_id={} qid={} cid={}",
+                    age as f64 / 1e9,
+                    disk,
+                    operation,
+                    key.ctrl_id,
+                    key.qid,
+                    key.cid
+                );
+            }
+            reported.insert((key, entry.from));
+            *stuck.entry((disk, operation)).or_default() += 1;
+        }
+        for (key, from) in lost {
+            // Unless the command id was reused in the meantime
+            if self.map.get(&key, 0).is_ok_and(|entry| entry.from == from) {
+                if let Err(e) = self.map.remove(&key) {
+                    warn!("failed to remove a lost command from STATE_TRACKER: {}", e);
+                }
+            }
+        }
+        self.reported = reported;
+        self.outstanding.set(outstanding);
+        self.stuck.reset();
+        for ((disk, operation), count) in stuck {
+            self.stuck
+                .with_label_values(&[&disk, &operation])
+                .set(count);
+        }
+    }
+}
+
+/// Age after which a command is lost rather than stuck: the kernel has given up on it
+/// by then (each of the `max_retries` + 1 attempts times out after the nvme_core
+/// `io_timeout`, or `admin_timeout`), so its completion was missed, e.g. because the
+/// controller was reset or the probes were detached in the meantime.
+fn lost_after(sysfs: &Path) -> Duration {
+    let parameter = |name: &str, default: u64| {
+        let path = sysfs.join("module/nvme_core/parameters").join(name);
+        fs::read_to_string(path)
+            .ok()
+            .and_then(|value| value.trim().parse().ok())
+            .unwrap_or(default)
+    };
+    let timeout = parameter("io_timeout", 30).max(parameter("admin_timeout", 60));
+    Duration::from_secs(timeout * (parameter("max_retries", 5) + 1))
+}
+
+fn monotonic_ns() -> Option<u64> {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    if unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) } != 0 {
+        warn!(
+            "failed to read CLOCK_MONOTONIC: {}",
+            std::io::Error::last_os_error()
+        );
+        return None;
+    }
+    Some(ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64)
+}
+
+/// Exposes the gauges of a `StuckCommandsScanner`.
+pub struct StuckCommandsMetrics {
+    outstanding: IntGauge,
+    stuck: IntGaugeVec,
+}
+
+impl Collector for StuckCommandsMetrics {
+    fn desc(&self) -> Vec<&Desc> {
+        self.outstanding
+            .desc()
+            .into_iter()
+            .chain(self.stuck.desc())
+            .collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.outstanding
+            .collect()
+            .into_iter()
+            .chain(self.stuck.collect())
+            .collect()
+    }
+}
+
+/// Keeps `NVME_BLOCK_SIZES` in sync with the logical block size of the NVMe disks in
+/// sysfs, which the eBPF programs need to turn block counts into bytes.
+pub struct BlockSizes {
+    map: HashMap<MapData, [u8; DISK_NAME_LEN], u32>,
+    sysfs: PathBuf,
+}
+
+impl BlockSizes {
+    pub fn new<P: Into<PathBuf>>(
+        map: HashMap<MapData, [u8; DISK_NAME_LEN], u32>,
+        sysfs: P,
+    ) -> Self {
+        BlockSizes {
+            map,
+            sysfs: sysfs.into(),
+        }
+    }
+
+    /// Refresh the sizes every 10 seconds, forever, to pick up hotplugged and
+    /// reformatted namespaces.
+    pub async fn run(mut self) {
+        let mut interval = tokio::time::interval(Duration::from_secs(10));
+        loop {
+            interval.tick().await;
+            self.refresh();
+        }
+    }
+
+    fn refresh(&mut self) {
+        let sizes = self.read();
+        let stale: Vec<[u8; DISK_NAME_LEN]> = self
+            .map
+            .keys()
+            .filter_map(|key| key.ok())
+            .filter(|key| !sizes.contains_key(key))
+            .collect();
+        for disk in stale {
+            let _ = self.map.remove(&disk);
+        }
+        for (disk, size) in sizes {
+            if let Err(e) = self.map.insert(disk, size, 0) {
+                warn!("failed to update NVME_BLOCK_SIZES: {}", e);
+            }
+        }
+    }
+
+    /// Logical block size of each NVMe disk, keyed by its NUL padded name.
+    fn read(&self) -> BTreeMap<[u8; DISK_NAME_LEN], u32> {
+        let dir = self.sysfs.join("class/block");
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("failed to list {}: {}", dir.display(), e);
+                return BTreeMap::new();
+            }
+        };
+        let mut sizes = BTreeMap::new();
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !name.starts_with("nvme") || name.len() >= DISK_NAME_LEN {
+                continue;
+            }
+            // Partitions and controllers have no queue
+            let path = entry.path().join("queue/logical_block_size");
+            let size = match fs::read_to_string(&path).map(|s| s.trim().parse::<u32>()) {
+                Ok(Ok(size)) => size,
+                _ => {
+                    debug!("no logical block size in {}", path.display());
+                    continue;
+                }
+            };
+            let mut disk = [0u8; DISK_NAME_LEN];
+            disk[..name.len()].copy_from_slice(name.as_bytes());
+            sizes.insert(disk, size);
+        }
+        sizes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loses_commands_after_the_kernel_timeouts() {
+        let sysfs = tempfile::tempdir().unwrap();
+        assert_eq!(lost_after(sysfs.path()), Duration::from_secs(360));
+
+        let parameters = sysfs.path().join("module/nvme_core/parameters");
+        fs::create_dir_all(&parameters).unwrap();
+        fs::write(parameters.join("io_timeout"), "90\n").unwrap();
+        fs::write(parameters.join("admin_timeout"), "60\n").unwrap();
+        fs::write(parameters.join("max_retries"), "2\n").unwrap();
+        assert_eq!(lost_after(sysfs.path()), Duration::from_secs(270));
+    }
+
+    #[test]
+    fn names_known_statuses() {
+        assert_eq!(status_name(0x000), "generic/success");
+        assert_eq!(status_name(0x281), "media/unrecovered_read_error");
+        assert_eq!(status_name(0x302), "path/ana_inaccessible");
+        assert_eq!(status_name(0x1b9), "command_specific/zone_full");
+    }
+
+    #[test]
+    fn ignores_the_dnr_and_more_bits() {
+        // Do Not Retry
+        assert_eq!(status_name(0x4281), "media/unrecovered_read_error");
+        // More
+        assert_eq!(status_name(0x2302), "path/ana_inaccessible");
+        assert_eq!(status_name(0x6004), "generic/data_xfer_error");
+    }
+
+    #[test]
+    fn names_unknown_statuses_by_code() {
+        assert_eq!(status_name(0x0ff), "generic/0xff");
+        assert_eq!(status_name(0x7c0), "vendor/0xc0");
+        assert_eq!(status_name(0x4405), "sct_4/0x05");
+    }
+}
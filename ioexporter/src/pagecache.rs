@@ -0,0 +1,249 @@
+use std::sync::Mutex;
+
+use aya::maps::{MapData, PerCpuArray};
+use ioexporter_common::pagecache::{
+    ADD_TO_PAGE_LRU_COUNTER_IDX, MARK_BUFFER_DIRTY_COUNTER_IDX, MARK_PAGE_ACCESSED_COUNTER_IDX,
+};
+use log::warn;
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::{MetricFamily, MetricType};
+
+use crate::filter::SharedSettings;
+use crate::metrics::{family, new_desc};
+use crate::probes::ProbeGroup;
+
+/// Page cache kprobe programs with the kernel functions they can be attached to.
+/// Folio-era kernels (>= 5.16) replaced the page based functions, so the folio
+/// variants are preferred when they exist.
+pub const PROBES: [(&str, &[&str]); 3] = [
+    (
+        "mark_page_accessed",
+        &["folio_mark_accessed", "mark_page_accessed"],
+    ),
+    (
+        "add_to_page_cache_lru",
+        &["filemap_add_folio", "add_to_page_cache_lru"],
+    ),
+    ("mark_buffer_dirty", &["mark_buffer_dirty"]),
+];
+
+const COUNTERS: [(u32, &str, &str); 3] = [
+    (
+        MARK_PAGE_ACCESSED_COUNTER_IDX,
+        "page_cache_accesses_total",
+        "Number of page cache accesses (mark_page_accessed/folio_mark_accessed)",
+    ),
+    (
+        ADD_TO_PAGE_LRU_COUNTER_IDX,
+        "page_cache_additions_total",
+        "Number of pages added to the page cache (add_to_page_cache_lru/filemap_add_folio)",
+    ),
+    (
+        MARK_BUFFER_DIRTY_COUNTER_IDX,
+        "buffer_dirty_total",
+        "Number of buffers marked dirty (mark_buffer_dirty)",
+    ),
+];
+
+const GAUGES: [(&str, &str); 3] = [
+    (
+        "page_cache_hits",
+        "Page cache hits since the previous scrape, as computed by cachestat",
+    ),
+    (
+        "page_cache_misses",
+        "Page cache misses since the previous scrape, as computed by cachestat",
+    ),
+    (
+        "page_cache_hit_ratio",
+        "Page cache hit ratio since the previous scrape, as computed by cachestat",
+    ),
+];
+
+/// Page cache effectiveness over an interval, derived the same way as bcc's cachestat.
+#[derive(Debug, PartialEq)]
+pub struct CacheStat {
+    pub hits: u64,
+    pub misses: u64,
+    pub ratio: f64,
+}
+
+impl CacheStat {
+    /// Compute the stats from the counter deltas of an interval.
+    /// Dirtied buffers are writes rather than lookups, so they are removed from the accesses,
+    /// and every page added to the LRU is a page that had to be read in (a miss).
+    pub fn from_deltas(accesses: u64, additions: u64, dirtied: u64) -> Self {
+        let total = accesses.saturating_sub(dirtied);
+        let misses = additions.min(total);
+        let hits = total - misses;
+        let ratio = if total == 0 {
+            0.0
+        } else {
+            hits as f64 / total as f64
+        };
+        CacheStat {
+            hits,
+            misses,
+            ratio,
+        }
+    }
+}
+
+/// Exposes the `PAGE_CACHE_METRICS` counters, summed over all CPUs on each scrape,
+/// and the cachestat hits/misses/ratio over the interval since the previous scrape.
+///
+/// The interval is shared by every client: when several Prometheus servers scrape the
+/// same exporter, each one gets the ratio since the scrape of the other. Prefer the
+/// counters with `rate()` in that case.
+pub struct PageCacheCollector {
+    map: PerCpuArray<MapData, u64>,
+    settings: SharedSettings,
+    counter_descs: Vec<Desc>,
+    gauge_descs: Vec<Desc>,
+    // Counters at the previous scrape, None until the first scrape since the
+    // collector was (re-)enabled
+    previous: Mutex<Option<[u64; 3]>>,
+}
+
+impl PageCacheCollector {
+    /// Checks itself whether the page cache collector is enabled, rather than relying
+    /// on `Filtered`, so that it notices when it is disabled and starts a new interval
+    /// once re-enabled.
+    pub fn new(
+        map: PerCpuArray<MapData, u64>,
+        settings: SharedSettings,
+    ) -> Result<Self, prometheus::Error> {
+        let counter_descs = COUNTERS
+            .iter()
+            .map(|(_, name, help)| new_desc(name, help, &[]))
+            .collect::<Result<_, _>>()?;
+        let gauge_descs = GAUGES
+            .iter()
+            .map(|(name, help)| new_desc(name, help, &[]))
+            .collect::<Result<_, _>>()?;
+        Ok(PageCacheCollector {
+            map,
+            settings,
+            counter_descs,
+            gauge_descs,
+            // The counters start at zero when the programs are loaded
+            previous: Mutex::new(Some([0; 3])),
+        })
+    }
+
+    fn read(&self, idx: u32) -> Option<u64> {
+        match self.map.get(&idx, 0) {
+            Ok(values) => Some(values.iter().sum()),
+            Err(e) => {
+                warn!("failed to read page cache counter {}: {}", idx, e);
+                None
+            }
+        }
+    }
+
+    /// Stats since the previous scrape, None on the first scrape of an interval.
+    fn cachestat(&self, current: [u64; 3]) -> Option<CacheStat> {
+        let mut previous = self.previous.lock().unwrap();
+        let stat = previous.map(|previous| {
+            let delta = |i: usize| current[i].saturating_sub(previous[i]);
+            CacheStat::from_deltas(delta(0), delta(1), delta(2))
+        });
+        *previous = Some(current);
+        stat
+    }
+}
+
+impl Collector for PageCacheCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.counter_descs
+            .iter()
+            .chain(self.gauge_descs.iter())
+            .collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        if !self
+            .settings
+            .read()
+            .unwrap()
+            .collector_enabled(ProbeGroup::PageCache)
+        {
+            *self.previous.lock().unwrap() = None;
+            return vec![];
+        }
+        let values: Vec<Option<u64>> = COUNTERS.iter().map(|(idx, _, _)| self.read(*idx)).collect();
+
+        let mut families: Vec<MetricFamily> = values
+            .iter()
+            .zip(self.counter_descs.iter())
+            .filter_map(|(value, desc)| {
+                value.map(|value| family(desc, MetricType::COUNTER, [(vec![], value as f64)]))
+            })
+            .collect();
+
+        if let [Some(accesses), Some(additions), Some(dirtied)] = values[..] {
+            let Some(stat) = self.cachestat([accesses, additions, dirtied]) else {
+                return families;
+            };
+            let gauges = [stat.hits as f64, stat.misses as f64, stat.ratio];
+            families.extend(
+                gauges
+                    .iter()
+                    .zip(self.gauge_descs.iter())
+                    .map(|(value, desc)| family(desc, MetricType::GAUGE, [(vec![], *value)])),
+            );
+        }
+        families
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn computes_hits_and_misses() {
+        assert_eq!(
+            CacheStat::from_deltas(100, 10, 20),
+            CacheStat {
+                hits: 70,
+                misses: 10,
+                ratio: 0.875,
+            }
+        );
+    }
+
+    #[test]
+    fn clamps_at_zero() {
+        // More pages added than looked up, e.g. readahead
+        assert_eq!(
+            CacheStat::from_deltas(10, 50, 5),
+            CacheStat {
+                hits: 0,
+                misses: 5,
+                ratio: 0.0,
+            }
+        );
+        // More buffers dirtied than pages accessed
+        assert_eq!(
+            CacheStat::from_deltas(5, 3, 10),
+            CacheStat {
+                hits: 0,
+                misses: 0,
+                ratio: 0.0,
+            }
+        );
+    }
+
+    #[test]
+    fn has_no_ratio_without_accesses() {
+        assert_eq!(
+            CacheStat::from_deltas(0, 0, 0),
+            CacheStat {
+                hits: 0,
+                misses: 0,
+                ratio: 0.0,
+            }
+        );
+    }
+}
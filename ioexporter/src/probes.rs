@@ -0,0 +1,356 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::anyhow;
+use aya::maps::Array;
+use aya::programs::kprobe::KProbeLinkId;
+use aya::programs::tp_btf::BtfTracePointLinkId;
+use aya::programs::trace_point::TracePointLinkId;
+use aya::programs::{BtfTracePoint, KProbe, Program, TracePoint};
+use aya::{Bpf, Btf};
+use ioexporter_common::config::CONFIG_MAP;
+use log::{debug, info, warn};
+use prometheus::{IntGaugeVec, Opts};
+
+use crate::kallsyms::KernelSymbols;
+use crate::pagecache;
+
+/// Set of eBPF programs feeding one collector, attached and detached together.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ProbeGroup {
+    PageCache,
+    Block,
+    Nvme,
+}
+
+impl ProbeGroup {
+    pub const ALL: [ProbeGroup; 3] = [ProbeGroup::PageCache, ProbeGroup::Block, ProbeGroup::Nvme];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProbeGroup::PageCache => "pagecache",
+            ProbeGroup::Block => "block",
+            ProbeGroup::Nvme => "nvme",
+        }
+    }
+
+    fn targets(&self) -> Vec<Target> {
+        match self {
+            ProbeGroup::PageCache => pagecache::PROBES
+                .iter()
+                .map(|(program, functions)| Target::KProbe { program, functions })
+                .collect(),
+            ProbeGroup::Block => vec![
+                Target::BtfTracePoint {
+                    program: "block_rq_insert",
+                },
+                Target::BtfTracePoint {
+                    program: "block_rq_issue",
+                },
+                Target::BtfTracePoint {
+                    program: "block_rq_merge",
+                },
+                Target::BtfTracePoint {
+                    program: "block_rq_complete",
+                },
+            ],
+            // sudo ls /sys/kernel/debug/tracing/events/ to find category
+            ProbeGroup::Nvme => vec![
+                Target::TracePoint {
+                    program: "nvme_setup_cmd",
+                    category: "nvme",
+                },
+                Target::TracePoint {
+                    program: "nvme_complete_rq",
+                    category: "nvme",
+                },
+            ],
+        }
+    }
+}
+
+enum Target {
+    /// Kprobe attached to the first of `functions` available in the running kernel.
+    KProbe {
+        program: &'static str,
+        functions: &'static [&'static str],
+    },
+    /// BTF tracepoint with the same name as its program.
+    BtfTracePoint { program: &'static str },
+    /// Tracepoint with the same name as its program.
+    TracePoint {
+        program: &'static str,
+        category: &'static str,
+    },
+}
+
+impl Target {
+    fn program(&self) -> &'static str {
+        match self {
+            Target::KProbe { program, .. } => program,
+            Target::BtfTracePoint { program } => program,
+            Target::TracePoint { program, .. } => program,
+        }
+    }
+}
+
+enum Link {
+    KProbe(&'static str, KProbeLinkId),
+    BtfTracePoint(&'static str, BtfTracePointLinkId),
+    TracePoint(&'static str, TracePointLinkId),
+}
+
+/// Clears the state the eBPF programs of a group keep between events, e.g. the
+/// requests in flight, which would be stale once the programs missed some events.
+pub type Reset = Box<dyn FnMut() -> Result<(), anyhow::Error> + Send>;
+
+/// Owns the loaded eBPF object and keeps track of which probe groups are attached.
+pub struct Probes {
+    bpf: Bpf,
+    symbols: Option<KernelSymbols>,
+    loaded: HashSet<&'static str>,
+    links: HashMap<ProbeGroup, Vec<Link>>,
+    resets: HashMap<ProbeGroup, Vec<Reset>>,
+    up: IntGaugeVec,
+}
+
+impl Probes {
+    pub fn new(bpf: Bpf, symbols: Option<KernelSymbols>) -> Result<Self, prometheus::Error> {
+        let up = IntGaugeVec::new(
+            Opts::new(
+                "ioexporter_probe_up",
+                "Whether the eBPF programs of a probe group are attached",
+            ),
+            &["probe"],
+        )?;
+        for group in ProbeGroup::ALL {
+            up.with_label_values(&[group.name()]).set(0);
+        }
+        Ok(Probes {
+            bpf,
+            symbols,
+            loaded: HashSet::new(),
+            links: HashMap::new(),
+            resets: HashMap::new(),
+            up,
+        })
+    }
+
+    /// Gauge to register so that dashboards can tell which collectors are active.
+    pub fn up(&self) -> IntGaugeVec {
+        self.up.clone()
+    }
+
+    pub fn is_attached(&self, group: ProbeGroup) -> bool {
+        self.links.contains_key(&group)
+    }
+
+    /// Run `reset` whenever `group` is detached and before it is attached again.
+    pub fn on_reset(&mut self, group: ProbeGroup, reset: Reset) {
+        self.resets.entry(group).or_default().push(reset);
+    }
+
+    /// Attach every program of `group`. On failure, the programs attached so far are
+    /// detached again so the group is either fully up or fully down.
+    pub fn attach(&mut self, group: ProbeGroup) -> bool {
+        self.reset(group);
+        for target in group.targets() {
+            match self.attach_target(&target) {
+                Ok(link) => self.links.entry(group).or_default().push(link),
+                Err(e) => {
+                    warn!(
+                        "failed to attach {}, disabling {} collector: {:#}",
+                        target.program(),
+                        group.name(),
+                        e
+                    );
+                    self.detach(group);
+                    return false;
+                }
+            }
+        }
+        info!("{} collector enabled", group.name());
+        self.up.with_label_values(&[group.name()]).set(1);
+        true
+    }
+
+    pub fn detach(&mut self, group: ProbeGroup) {
+        for link in self.links.remove(&group).unwrap_or_default() {
+            if let Err(e) = self.detach_link(link) {
+                warn!("failed to detach {} program: {:#}", group.name(), e);
+            }
+        }
+        self.reset(group);
+        self.up.with_label_values(&[group.name()]).set(0);
+    }
+
+    fn reset(&mut self, group: ProbeGroup) {
+        for reset in self.resets.get_mut(&group).into_iter().flatten() {
+            if let Err(e) = reset() {
+                warn!("failed to reset {} state: {:#}", group.name(), e);
+            }
+        }
+    }
+
+    /// Write `value` at `idx` in the `CONFIG` map read by the eBPF programs.
+    pub fn set_config(&mut self, idx: u32, value: u32) -> Result<(), anyhow::Error> {
+        let map = self
+            .bpf
+            .map_mut(CONFIG_MAP)
+            .ok_or_else(|| anyhow!("map CONFIG not found"))?;
+        let mut config: Array<_, u32> = Array::try_from(map)?;
+        config.set(idx, value, 0)?;
+        Ok(())
+    }
+
+    fn attach_target(&mut self, target: &Target) -> Result<Link, anyhow::Error> {
+        let Probes {
+            bpf,
+            symbols,
+            loaded,
+            ..
+        } = self;
+        let name = target.program();
+        let link = match *target {
+            Target::KProbe { functions, .. } => {
+                let program: &mut KProbe = program_mut(bpf, name)?.try_into()?;
+                if !loaded.contains(name) {
+                    program.load()?;
+                    loaded.insert(name);
+                }
+                let attached = candidates(symbols.as_ref(), functions)
+                    .into_iter()
+                    .find_map(|function| match program.attach(function, 0) {
+                        Ok(link) => Some((function, link)),
+                        Err(e) => {
+                            debug!("failed to attach {} to {}: {}", name, function, e);
+                            None
+                        }
+                    });
+                match attached {
+                    Some((function, link)) => {
+                        debug!("attached {} to {}", name, function);
+                        Link::KProbe(name, link)
+                    }
+                    None => anyhow::bail!("none of {:?} could be probed", functions),
+                }
+            }
+            Target::BtfTracePoint { .. } => {
+                let program: &mut BtfTracePoint = program_mut(bpf, name)?.try_into()?;
+                if !loaded.contains(name) {
+                    let btf = Btf::from_sys_fs()?;
+                    program.load(name, &btf)?;
+                    loaded.insert(name);
+                }
+                Link::BtfTracePoint(name, program.attach()?)
+            }
+            Target::TracePoint { category, .. } => {
+                let program: &mut TracePoint = program_mut(bpf, name)?.try_into()?;
+                if !loaded.contains(name) {
+                    program.load()?;
+                    loaded.insert(name);
+                }
+                Link::TracePoint(name, program.attach(category, name)?)
+            }
+        };
+        Ok(link)
+    }
+
+    fn detach_link(&mut self, link: Link) -> Result<(), anyhow::Error> {
+        match link {
+            Link::KProbe(name, link) => {
+                let program: &mut KProbe = program_mut(&mut self.bpf, name)?.try_into()?;
+                program.detach(link)?;
+            }
+            Link::BtfTracePoint(name, link) => {
+                let program: &mut BtfTracePoint = program_mut(&mut self.bpf, name)?.try_into()?;
+                program.detach(link)?;
+            }
+            Link::TracePoint(name, link) => {
+                let program: &mut TracePoint = program_mut(&mut self.bpf, name)?.try_into()?;
+                program.detach(link)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Functions a kprobe may be attached to, in order of preference: those that exist
+/// in the running kernel, or all of them when its symbols could not be read.
+fn candidates<'a>(symbols: Option<&KernelSymbols>, functions: &[&'a str]) -> Vec<&'a str> {
+    functions
+        .iter()
+        .copied()
+        .filter(|function| symbols.is_none_or(|s| s.contains(function)))
+        .collect()
+}
+
+fn program_mut<'a>(bpf: &'a mut Bpf, name: &str) -> Result<&'a mut Program, anyhow::Error> {
+    bpf.program_mut(name)
+        .ok_or_else(|| anyhow!("program {} not found", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// First function each page cache program would be attached to.
+    fn page_cache_probes(kallsyms: &str) -> Vec<Option<&'static str>> {
+        let symbols = KernelSymbols::parse(kallsyms);
+        pagecache::PROBES
+            .iter()
+            .map(|(_, functions)| candidates(Some(&symbols), functions).first().copied())
+            .collect()
+    }
+
+    #[test]
+    fn prefers_folio_functions() {
+        let kallsyms = "\
+ffffffff812a1c30 T folio_mark_accessed
+ffffffff812a1d10 T mark_page_accessed
+ffffffff8128f4e0 T filemap_add_folio
+ffffffff8137b2a0 T mark_buffer_dirty
+ffffffff82c1e000 D filemap_add_folio_data
+";
+        assert_eq!(
+            page_cache_probes(kallsyms),
+            [
+                Some("folio_mark_accessed"),
+                Some("filemap_add_folio"),
+                Some("mark_buffer_dirty"),
+            ]
+        );
+    }
+
+    #[test]
+    fn falls_back_to_page_functions() {
+        let kallsyms = "\
+ffffffff81227a80 T mark_page_accessed
+ffffffff8121a9d0 T add_to_page_cache_lru
+ffffffff812fc5e0 t mark_buffer_dirty
+ffffffffc0a01000 t nvme_setup_cmd\t[nvme_core]
+";
+        assert_eq!(
+            page_cache_probes(kallsyms),
+            [
+                Some("mark_page_accessed"),
+                Some("add_to_page_cache_lru"),
+                Some("mark_buffer_dirty"),
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_data_symbols() {
+        let symbols = KernelSymbols::parse("ffffffff82c1e000 D mark_buffer_dirty\n");
+        assert!(!symbols.contains("mark_buffer_dirty"));
+        assert_eq!(page_cache_probes("")[2], None);
+    }
+
+    #[test]
+    fn tries_every_function_without_symbols() {
+        assert_eq!(
+            candidates(None, &["folio_mark_accessed", "mark_page_accessed"]),
+            ["folio_mark_accessed", "mark_page_accessed"]
+        );
+    }
+}
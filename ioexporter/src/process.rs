@@ -0,0 +1,135 @@
+use aya::maps::{MapData, PerCpuHashMap};
+use ioexporter_common::attribution::{IoStats, ProcessKey};
+use log::warn;
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+
+use crate::stats::{self, IoStatsDescs};
+
+/// Exposes per process counters of a `PROCESS_*_STATS` map, limited to the `top_n`
+/// processes with the most bytes (then requests) to bound the cardinality. Meant to be
+/// wrapped in a `Filtered` only enabled with process attribution.
+pub struct ProcessCollector {
+    map: PerCpuHashMap<MapData, ProcessKey, IoStats>,
+    top_n: usize,
+    descs: IoStatsDescs,
+}
+
+impl ProcessCollector {
+    /// `prefix` is the name prefix of the exported counters. The bytes counter is
+    /// only exported `with_bytes`, for sources that know the request sizes.
+    pub fn new(
+        map: PerCpuHashMap<MapData, ProcessKey, IoStats>,
+        prefix: &str,
+        with_bytes: bool,
+        top_n: usize,
+    ) -> Result<Self, prometheus::Error> {
+        let descs = IoStatsDescs::new(
+            prefix,
+            "per submitting process",
+            &["pid", "comm"],
+            with_bytes,
+        )?;
+        Ok(ProcessCollector { map, top_n, descs })
+    }
+
+    fn top(&self) -> Vec<(ProcessKey, IoStats)> {
+        let processes = self
+            .map
+            .iter()
+            .filter_map(|entry| match entry {
+                Ok((process, values)) => Some((process, stats::total(values.iter()))),
+                Err(e) => {
+                    warn!("failed to read process stats: {}", e);
+                    None
+                }
+            })
+            .collect();
+        top(processes, self.top_n)
+    }
+}
+
+/// The `n` processes with the most bytes, then requests.
+fn top(mut processes: Vec<(ProcessKey, IoStats)>, n: usize) -> Vec<(ProcessKey, IoStats)> {
+    processes.sort_by_key(|(_, s)| std::cmp::Reverse((s.bytes, s.requests)));
+    processes.truncate(n);
+    processes
+}
+
+impl Collector for ProcessCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.descs()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let processes: Vec<(Vec<String>, IoStats)> = self
+            .top()
+            .into_iter()
+            .map(|(process, stats)| (vec![process.tgid.to_string(), comm(&process)], stats))
+            .collect();
+        self.descs.families(&processes)
+    }
+}
+
+fn comm(process: &ProcessKey) -> String {
+    let len = process
+        .comm
+        .iter()
+        .position(|c| *c == 0)
+        .unwrap_or(process.comm.len());
+    String::from_utf8_lossy(&process.comm[..len]).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(tgid: u32, comm: &str, requests: u64, bytes: u64) -> (ProcessKey, IoStats) {
+        let mut key = ProcessKey {
+            tgid,
+            comm: [0; 16],
+        };
+        key.comm[..comm.len()].copy_from_slice(comm.as_bytes());
+        let stats = IoStats {
+            requests,
+            bytes,
+            latency_ns: 0,
+        };
+        (key, stats)
+    }
+
+    fn tgids(processes: &[(ProcessKey, IoStats)]) -> Vec<u32> {
+        processes.iter().map(|(p, _)| p.tgid).collect()
+    }
+
+    #[test]
+    fn orders_by_bytes_then_requests() {
+        let processes = vec![
+            process(1, "a", 10, 4096),
+            process(2, "b", 1, 8192),
+            process(3, "c", 20, 4096),
+            process(4, "d", 100, 0),
+        ];
+        assert_eq!(tgids(&top(processes, 10)), [2, 3, 1, 4]);
+    }
+
+    #[test]
+    fn keeps_the_top_n() {
+        let processes = vec![
+            process(1, "a", 1, 512),
+            process(2, "b", 1, 4096),
+            process(3, "c", 1, 1024),
+        ];
+        assert_eq!(tgids(&top(processes.clone(), 2)), [2, 3]);
+        assert!(top(processes, 0).is_empty());
+    }
+
+    #[test]
+    fn trims_the_comm() {
+        assert_eq!(comm(&process(1, "postgres", 0, 0).0), "postgres");
+        assert_eq!(
+            comm(&process(1, "0123456789abcdef", 0, 0).0),
+            "0123456789abcdef"
+        );
+    }
+}
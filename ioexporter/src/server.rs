@@ -0,0 +1,72 @@
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::{info, warn};
+use prometheus::{Encoder, Registry, TextEncoder};
+
+const LANDING_PAGE: &str = "<html>
+<head><title>IO Exporter</title></head>
+<body>
+<h1>IO Exporter</h1>
+<p><a href=\"/metrics\">Metrics</a></p>
+</body>
+</html>
+";
+
+/// Serve the registry on `addr` until `shutdown` resolves.
+/// The registry is gathered on every scrape of `/metrics`.
+pub async fn serve<F>(
+    addr: SocketAddr,
+    registry: Registry,
+    shutdown: F,
+) -> Result<(), anyhow::Error>
+where
+    F: Future<Output = ()>,
+{
+    let make_svc = make_service_fn(move |_conn| {
+        let registry = registry.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let registry = registry.clone();
+                async move { Ok::<_, Infallible>(handle(req, &registry)) }
+            }))
+        }
+    });
+
+    let server = Server::try_bind(&addr)?.serve(make_svc);
+    info!("Listening on http://{}", server.local_addr());
+    server.with_graceful_shutdown(shutdown).await?;
+    Ok(())
+}
+
+fn handle(req: Request<Body>, registry: &Registry) -> Response<Body> {
+    match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => metrics(registry),
+        (&Method::GET, "/") => Response::new(Body::from(LANDING_PAGE)),
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from("Not Found\n"))
+            .unwrap(),
+    }
+}
+
+fn metrics(registry: &Registry) -> Response<Body> {
+    let encoder = TextEncoder::new();
+    let metric_families = registry.gather();
+    let mut buffer = vec![];
+    if let Err(e) = encoder.encode(&metric_families, &mut buffer) {
+        warn!("failed to encode metrics: {}", e);
+        return Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(e.to_string()))
+            .unwrap();
+    }
+    Response::builder()
+        .header(CONTENT_TYPE, encoder.format_type())
+        .body(Body::from(buffer))
+        .unwrap()
+}
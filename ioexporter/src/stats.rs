@@ -0,0 +1,90 @@
+use ioexporter_common::attribution::IoStats;
+use prometheus::core::Desc;
+use prometheus::proto::{MetricFamily, MetricType};
+
+use crate::metrics::{family, new_desc};
+
+/// Sum the per-CPU values of a `LruPerCpuHashMap` entry.
+pub fn total<'a, I: IntoIterator<Item = &'a IoStats>>(values: I) -> IoStats {
+    values
+        .into_iter()
+        .fold(IoStats::default(), |acc, s| IoStats {
+            requests: acc.requests + s.requests,
+            bytes: acc.bytes + s.bytes,
+            latency_ns: acc.latency_ns + s.latency_ns,
+        })
+}
+
+/// Counters `<prefix>_requests_total`, `<prefix>_bytes_total` and
+/// `<prefix>_latency_seconds_total` exported from `IoStats`.
+pub struct IoStatsDescs {
+    requests: Desc,
+    bytes: Option<Desc>,
+    latency: Desc,
+}
+
+impl IoStatsDescs {
+    /// `per` completes the help texts, e.g. "per submitting process". The bytes counter
+    /// is only exported `with_bytes`, for sources that know the request sizes.
+    pub fn new(
+        prefix: &str,
+        per: &str,
+        labels: &[&str],
+        with_bytes: bool,
+    ) -> Result<Self, prometheus::Error> {
+        let requests = new_desc(
+            &format!("{}_requests_total", prefix),
+            &format!("Number of IO requests completed, {}", per),
+            labels,
+        )?;
+        let bytes = match with_bytes {
+            true => Some(new_desc(
+                &format!("{}_bytes_total", prefix),
+                &format!("Number of bytes of the IO requests completed, {}", per),
+                labels,
+            )?),
+            false => None,
+        };
+        let latency = new_desc(
+            &format!("{}_latency_seconds_total", prefix),
+            &format!("Cumulated latency of the IO requests completed, {}", per),
+            labels,
+        )?;
+        Ok(IoStatsDescs {
+            requests,
+            bytes,
+            latency,
+        })
+    }
+
+    pub fn descs(&self) -> Vec<&Desc> {
+        [
+            Some(&self.requests),
+            self.bytes.as_ref(),
+            Some(&self.latency),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    /// Build the families from the stats of each set of label values.
+    pub fn families(&self, stats: &[(Vec<String>, IoStats)]) -> Vec<MetricFamily> {
+        let counter = |desc: &Desc, value: fn(&IoStats) -> f64| {
+            family(
+                desc,
+                MetricType::COUNTER,
+                stats
+                    .iter()
+                    .map(|(labels, stats)| (labels.clone(), value(stats))),
+            )
+        };
+
+        let mut families = vec![counter(&self.requests, |s| s.requests as f64)];
+        if let Some(bytes) = &self.bytes {
+            families.push(counter(bytes, |s| s.bytes as f64));
+        }
+        families.push(counter(&self.latency, |s| s.latency_ns as f64 / 1e9));
+        families
+    }
+}